@@ -0,0 +1,559 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use clap::Parser;
+use serde::{Deserialize, Deserializer};
+
+use crate::custom_err::{CustomResult, invalid_argument_err};
+use crate::store::record::{HEADER_LEN, MAX_BODY_LEN};
+use crate::tools::Command;
+
+// 根路径下已经使用的路径，以及没有名称的数据库挂在根路径下的接口，数据库名称不能和它们相同
+const RESERVED_DB_NAMES: [&str; 8] = ["health", "ready", "metrics", "stats", "admin", "get", "set", "set_sync"];
+
+/// 单个数据库的配置
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // 数据库名称，HTTP接口挂在 /{name} 下面，为空时挂在根路径
+    pub name: String,
+    // 数据文件存储的目录
+    pub workspace: String,
+    // 单个数据文件最大值，超过后就会写新文件
+    pub max_file_size: u32,
+    // 最多允许的数据文件个数，超过后就会开始整理合并
+    pub max_file_num: u32,
+    // 启动时并发恢复索引的文件数
+    pub recover_parallel: usize,
+    // 读缓存的容量（字节），0表示不开启
+    pub value_cache_size: u64,
+    // 已封存（不再写入）的数据文件，是否使用mmap读取
+    pub mmap_sealed_file: bool,
+    // 读数据时最多同时打开的文件数
+    pub max_open_files: usize,
+    // 数据落盘的策略，单个写入请求可以覆盖
+    pub durability: Durability,
+    // 每批最多写入的条数
+    pub write_batch_size: usize,
+    // 每批最多写入的字节数，达到后就不再等待更多数据
+    pub write_batch_bytes: usize,
+    // 写入队列的长度
+    pub write_queue_size: usize,
+    // 写入队列满时，最多等待的毫秒数，0表示一直等待
+    pub write_push_timeout_ms: u64,
+    // 写入队列满时，是否直接返回失败，不再等待
+    pub write_fast_fail: bool,
+    // 关闭时是否为正在写入的文件生成索引文件，加快下次启动
+    pub checkpoint_on_shutdown: bool,
+    // 只读模式，不获取工作目录的锁，也不启动写入和整理任务
+    pub read_only: bool,
+    // 整理任务执行的间隔（秒）
+    pub compaction_interval_secs: u64,
+    // 整理任务读写数据文件的限速（字节/秒），0表示不限速，运行时可以修改
+    pub compaction_rate_limit: u64,
+    // 只在这些时间段内执行定时整理，为空表示不限制
+    pub compaction_windows: Vec<TimeWindow>,
+    // 工作目录不存在时自动创建，只读模式不会创建
+    pub create_if_missing: bool,
+    // key的最大长度（字节）
+    pub max_key_size: usize,
+    // value的最大长度（字节）
+    pub max_value_size: usize,
+}
+
+/// 数据落盘的策略
+/// 配置中的写法：always、never、interval:<毫秒数>
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Durability {
+    // 每批数据写入后都执行fsync，落盘后才回执
+    Always,
+    // 每隔指定的毫秒数执行一次fsync，落盘后才回执
+    Interval(u64),
+    // 不主动fsync，交给操作系统，写入后就回执
+    Never,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            name: String::new(),
+            workspace: String::new(),
+            max_file_size: 1024 * 1024 * 1024,
+            max_file_num: 10,
+            recover_parallel: std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4),
+            value_cache_size: 64 * 1024 * 1024,
+            mmap_sealed_file: false,
+            max_open_files: 128,
+            durability: Durability::Always,
+            write_batch_size: 1000,
+            write_batch_bytes: 4 * 1024 * 1024,
+            write_queue_size: 10000,
+            write_push_timeout_ms: 0,
+            write_fast_fail: false,
+            checkpoint_on_shutdown: true,
+            read_only: false,
+            compaction_interval_secs: 10,
+            compaction_rate_limit: 0,
+            compaction_windows: Vec::new(),
+            create_if_missing: true,
+            max_key_size: 1024,
+            max_value_size: 1024 * 1024,
+        }
+    }
+}
+
+impl Config {
+    pub fn new(workspace: String) -> Config {
+        Config {
+            workspace,
+            ..Default::default()
+        }
+    }
+
+    /// 检查配置的取值是否合法
+    pub fn validate(&self) -> CustomResult<()> {
+        let db = if self.name.is_empty() { "默认" } else { &self.name };
+        let invalid = |msg: &str| Err(invalid_argument_err(format!("数据库[{}]配置不合法,{}", db, msg)));
+
+        if !self.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            return invalid("name 只能包含字母、数字、下划线和中划线");
+        }
+        if RESERVED_DB_NAMES.contains(&self.name.as_str()) {
+            return invalid(&format!("name 不能使用和接口路径相同的名称:{}", RESERVED_DB_NAMES.join(",")));
+        }
+        if self.workspace.is_empty() {
+            return invalid("workspace 不能为空");
+        }
+        let path = Path::new(&self.workspace);
+        if path.exists() && !path.is_dir() {
+            return invalid(&format!("workspace[{}] 必须是文件夹", self.workspace));
+        }
+        if !path.exists() && (!self.create_if_missing || self.read_only) {
+            return invalid(&format!("workspace[{}] 不存在", self.workspace));
+        }
+        if self.max_file_size == 0 {
+            return invalid("max_file_size 必须大于0");
+        }
+        // 为1时每次整理都会回收全部已封存的文件
+        if self.max_file_num < 2 {
+            return invalid("max_file_num 不能小于2");
+        }
+        if self.recover_parallel == 0 {
+            return invalid("recover_parallel 必须大于0");
+        }
+        if self.max_open_files == 0 {
+            return invalid("max_open_files 必须大于0");
+        }
+        if self.durability == Durability::Interval(0) {
+            return invalid("durability 的落盘间隔必须大于0");
+        }
+        if self.write_batch_size == 0 || self.write_batch_bytes == 0 {
+            return invalid("write_batch_size 和 write_batch_bytes 必须大于0");
+        }
+        if self.write_queue_size == 0 {
+            return invalid("write_queue_size 必须大于0");
+        }
+        if self.write_fast_fail && self.write_push_timeout_ms > 0 {
+            return invalid("write_fast_fail 和 write_push_timeout_ms 不能同时配置");
+        }
+        if self.compaction_interval_secs == 0 {
+            return invalid("compaction_interval_secs 必须大于0");
+        }
+        if self.max_key_size == 0 || self.max_value_size == 0 {
+            return invalid("max_key_size 和 max_value_size 必须大于0");
+        }
+        // 超过后恢复时会被当成损坏的记录
+        if self.max_key_size.saturating_add(self.max_value_size) > MAX_BODY_LEN {
+            return invalid(&format!("max_key_size 和 max_value_size 之和不能超过{}", MAX_BODY_LEN));
+        }
+        // 文件超过 max_file_size 之后才会切换，最后一批写入（最大的单条记录可能超过 write_batch_bytes）之后，偏移量仍然要能用u32表示
+        let max_offset = self.max_file_size as u64 + self.write_batch_bytes as u64
+            + (self.max_key_size + self.max_value_size + HEADER_LEN) as u64;
+        if max_offset > u32::MAX as u64 {
+            return invalid(&format!("max_file_size、write_batch_bytes 和单条记录的最大长度之和不能超过{}", u32::MAX));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Durability::Always),
+            "never" => Ok(Durability::Never),
+            _ => s.strip_prefix("interval:")
+                .and_then(|ms| ms.parse().ok())
+                .map(Durability::Interval)
+                .ok_or(format!("durability[{}]不合法,只能是 always、never 或 interval:<毫秒数>", s)),
+        }
+    }
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Durability::Always => write!(f, "always"),
+            Durability::Interval(ms) => write!(f, "interval:{}", ms),
+            Durability::Never => write!(f, "never"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Durability {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// 一天中的时间段，使用本地时间
+/// 配置中的写法：HH:MM-HH:MM，包含开始不包含结束，结束早于开始时表示跨过零点，例如 22:00-06:00
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeWindow {
+    // 从零点开始的分钟数
+    start: u32,
+    end: u32,
+}
+
+impl TimeWindow {
+    /// 指定的时间（从零点开始的分钟数）是否在时间段内
+    pub fn contains(&self, minute: u32) -> bool {
+        if self.start < self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_minute = |t: &str| -> Option<u32> {
+            let (h, m) = t.trim().split_once(':')?;
+            let (h, m): (u32, u32) = (h.parse().ok()?, m.parse().ok()?);
+            if h < 24 && m < 60 { Some(h * 60 + m) } else { None }
+        };
+        s.split_once('-')
+            .and_then(|(start, end)| Some(TimeWindow { start: parse_minute(start)?, end: parse_minute(end)? }))
+            .filter(|w| w.start != w.end)
+            .ok_or(format!("时间段[{}]不合法,格式是 HH:MM-HH:MM，开始和结束不能相同", s))
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}-{:02}:{:02}", self.start / 60, self.start % 60, self.end / 60, self.end % 60)
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeWindow {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// HTTP服务的配置
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    // 监听的地址
+    pub host: String,
+    // 监听的端口
+    pub port: u16,
+    // 处理请求的线程数
+    pub workers: usize,
+    // 日志配置文件的路径，文件不存在时输出到控制台
+    pub log_config: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            host: String::from("127.0.0.1"),
+            port: 8848,
+            workers: std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4),
+            log_config: String::from("log4rs.yaml"),
+        }
+    }
+}
+
+/// 配置文件的格式
+/// ```toml
+/// [server]
+/// port = 8848
+///
+/// [[db]]
+/// workspace = "/data/learn-db"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppConfig {
+    pub server: ServerConfig,
+    // 每个配置对应一个独立的数据库
+    #[serde(rename = "db")]
+    pub dbs: Vec<Config>,
+}
+
+/// 命令行参数，每个参数也可以通过环境变量设置，指定了子命令时执行对应的离线工具
+/// 优先级：命令行 > 环境变量 > 配置文件 > 默认值
+/// 数据库相关的参数对配置文件中的所有数据库生效，name 和 workspace 只能在只有一个数据库时使用
+#[derive(Parser, Debug, Default)]
+#[command(name = "learn-db", version, about = "一个基于 bitcask 模型的 KV 数据库")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// 配置文件的路径（TOML）
+    #[arg(short, long, env = "LEARN_DB_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// 监听的地址
+    #[arg(long, env = "LEARN_DB_HOST")]
+    pub host: Option<String>,
+    /// 监听的端口
+    #[arg(long, env = "LEARN_DB_PORT")]
+    pub port: Option<u16>,
+    /// 处理请求的线程数
+    #[arg(long, env = "LEARN_DB_WORKERS")]
+    pub workers: Option<usize>,
+    /// 日志配置文件的路径
+    #[arg(long, env = "LEARN_DB_LOG_CONFIG")]
+    pub log_config: Option<String>,
+    /// 数据库名称
+    #[arg(long, env = "LEARN_DB_NAME")]
+    pub name: Option<String>,
+    /// 数据文件存储的目录
+    #[arg(short, long, env = "LEARN_DB_WORKSPACE")]
+    pub workspace: Option<String>,
+    /// 单个数据文件最大值（字节）
+    #[arg(long, env = "LEARN_DB_MAX_FILE_SIZE")]
+    pub max_file_size: Option<u32>,
+    /// 最多允许的数据文件个数
+    #[arg(long, env = "LEARN_DB_MAX_FILE_NUM")]
+    pub max_file_num: Option<u32>,
+    /// 启动时并发恢复索引的文件数
+    #[arg(long, env = "LEARN_DB_RECOVER_PARALLEL")]
+    pub recover_parallel: Option<usize>,
+    /// 读缓存的容量（字节），0表示不开启
+    #[arg(long, env = "LEARN_DB_VALUE_CACHE_SIZE")]
+    pub value_cache_size: Option<u64>,
+    /// 已封存的数据文件是否使用mmap读取
+    #[arg(long, env = "LEARN_DB_MMAP_SEALED_FILE", num_args = 0..=1, default_missing_value = "true")]
+    pub mmap_sealed_file: Option<bool>,
+    /// 读数据时最多同时打开的文件数
+    #[arg(long, env = "LEARN_DB_MAX_OPEN_FILES")]
+    pub max_open_files: Option<usize>,
+    /// 数据落盘的策略：always、never、interval:<毫秒数>
+    #[arg(long, env = "LEARN_DB_DURABILITY")]
+    pub durability: Option<Durability>,
+    /// 每批最多写入的条数
+    #[arg(long, env = "LEARN_DB_WRITE_BATCH_SIZE")]
+    pub write_batch_size: Option<usize>,
+    /// 每批最多写入的字节数
+    #[arg(long, env = "LEARN_DB_WRITE_BATCH_BYTES")]
+    pub write_batch_bytes: Option<usize>,
+    /// 写入队列的长度
+    #[arg(long, env = "LEARN_DB_WRITE_QUEUE_SIZE")]
+    pub write_queue_size: Option<usize>,
+    /// 写入队列满时最多等待的毫秒数，0表示一直等待
+    #[arg(long, env = "LEARN_DB_WRITE_PUSH_TIMEOUT_MS")]
+    pub write_push_timeout_ms: Option<u64>,
+    /// 写入队列满时直接返回失败
+    #[arg(long, env = "LEARN_DB_WRITE_FAST_FAIL", num_args = 0..=1, default_missing_value = "true")]
+    pub write_fast_fail: Option<bool>,
+    /// 关闭时为正在写入的文件生成索引文件
+    #[arg(long, env = "LEARN_DB_CHECKPOINT_ON_SHUTDOWN", num_args = 0..=1, default_missing_value = "true")]
+    pub checkpoint_on_shutdown: Option<bool>,
+    /// 只读模式
+    #[arg(long, env = "LEARN_DB_READ_ONLY", num_args = 0..=1, default_missing_value = "true")]
+    pub read_only: Option<bool>,
+    /// 整理任务执行的间隔（秒）
+    #[arg(long, env = "LEARN_DB_COMPACTION_INTERVAL_SECS")]
+    pub compaction_interval_secs: Option<u64>,
+    /// 整理任务读写数据文件的限速（字节/秒），0表示不限速
+    #[arg(long, env = "LEARN_DB_COMPACTION_RATE_LIMIT")]
+    pub compaction_rate_limit: Option<u64>,
+    /// 只在这些时间段内执行定时整理，多个时间段用逗号分隔，例如 01:00-05:00,22:00-23:30
+    #[arg(long, env = "LEARN_DB_COMPACTION_WINDOWS", value_delimiter = ',')]
+    pub compaction_windows: Option<Vec<TimeWindow>>,
+    /// 工作目录不存在时自动创建
+    #[arg(long, env = "LEARN_DB_CREATE_IF_MISSING", num_args = 0..=1, default_missing_value = "true")]
+    pub create_if_missing: Option<bool>,
+    /// key的最大长度（字节）
+    #[arg(long, env = "LEARN_DB_MAX_KEY_SIZE")]
+    pub max_key_size: Option<usize>,
+    /// value的最大长度（字节）
+    #[arg(long, env = "LEARN_DB_MAX_VALUE_SIZE")]
+    pub max_value_size: Option<usize>,
+}
+
+/// 参数有值时覆盖配置
+macro_rules! override_fields {
+    ($src:expr, $dst:expr, $($field:ident),+) => {
+        $(if let Some(value) = &$src.$field {
+            $dst.$field = value.clone();
+        })+
+    };
+}
+
+impl AppConfig {
+    /// 读取配置文件，再用命令行参数和环境变量覆盖，最后检查配置是否合法
+    pub fn load(cli: &Cli) -> CustomResult<AppConfig> {
+        let mut app_config = match &cli.config {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .map_err(|e| invalid_argument_err(format!("读取配置文件{:?}失败,{}", path, e)))?;
+                toml::from_str(&content)
+                    .map_err(|e| invalid_argument_err(format!("解析配置文件{:?}失败,{}", path, e)))?
+            }
+            None => AppConfig::default(),
+        };
+        app_config.apply(cli)?;
+        app_config.validate()?;
+        Ok(app_config)
+    }
+
+    fn apply(&mut self, cli: &Cli) -> CustomResult<()> {
+        override_fields!(cli, self.server, host, port, workers, log_config);
+
+        if (cli.name.is_some() || cli.workspace.is_some()) && self.dbs.len() > 1 {
+            return Err(invalid_argument_err(String::from("配置了多个数据库时，name 和 workspace 只能在配置文件中设置")));
+        }
+        // 没有配置文件时，通过命令行指定的工作目录打开一个数据库
+        if let (true, Some(workspace)) = (self.dbs.is_empty(), &cli.workspace) {
+            self.dbs.push(Config::new(workspace.clone()));
+        }
+        for db in self.dbs.iter_mut() {
+            override_fields!(cli, db, name, workspace, max_file_size, max_file_num, recover_parallel,
+                value_cache_size, mmap_sealed_file, max_open_files, durability, write_batch_size,
+                write_batch_bytes, write_queue_size, write_push_timeout_ms, write_fast_fail,
+                checkpoint_on_shutdown, read_only, compaction_interval_secs, compaction_rate_limit,
+                compaction_windows, create_if_missing, max_key_size, max_value_size);
+        }
+        Ok(())
+    }
+
+    /// 检查每个数据库的配置，多个数据库之间，名称和工作目录都不能重复
+    pub fn validate(&self) -> CustomResult<()> {
+        if self.server.host.is_empty() {
+            return Err(invalid_argument_err(String::from("host 不能为空")));
+        }
+        if self.server.workers == 0 {
+            return Err(invalid_argument_err(String::from("workers 必须大于0")));
+        }
+        if self.dbs.is_empty() {
+            return Err(invalid_argument_err(String::from("至少需要配置一个数据库，可以使用 --workspace 或者配置文件中的 [[db]]")));
+        }
+
+        let mut names = HashSet::new();
+        let mut workspaces = HashSet::new();
+        for db in &self.dbs {
+            db.validate()?;
+            if !names.insert(db.name.clone()) {
+                return Err(invalid_argument_err(format!("数据库名称[{}]重复！", db.name)));
+            }
+            if !workspaces.insert(db.workspace.clone()) {
+                return Err(invalid_argument_err(format!("workspace[{}]被多个数据库使用！", db.workspace)));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use crate::config::{AppConfig, Cli, Durability};
+    use crate::test_util::test_workspace;
+
+    #[test]
+    pub fn test_file_and_cli_override() {
+        let (a, b) = (test_workspace("config-a"), test_workspace("config-b"));
+        let mut app_config: AppConfig = toml::from_str(&format!(r#"
+            [server]
+            port = 9000
+
+            [[db]]
+            name = "a"
+            workspace = "{}"
+            durability = "interval:100"
+
+            [[db]]
+            name = "b"
+            workspace = "{}"
+            max_file_num = 3
+        "#, a, b)).unwrap();
+        assert_eq!(app_config.server.host, "127.0.0.1");
+        assert_eq!(app_config.dbs[0].durability, Durability::Interval(100));
+        assert_eq!(app_config.dbs[1].write_batch_size, 1000);
+
+        let cli = Cli::try_parse_from(["learn-db", "--port", "9001", "--max-file-num", "5", "--read-only"]).unwrap();
+        app_config.apply(&cli).unwrap();
+        app_config.validate().unwrap();
+        assert_eq!(app_config.server.port, 9001);
+        assert!(app_config.dbs.iter().all(|db| db.max_file_num == 5 && db.read_only));
+
+        // 多个数据库时不能通过命令行指定工作目录
+        let cli = Cli::try_parse_from(["learn-db", "--workspace", a.as_str()]).unwrap();
+        assert!(app_config.apply(&cli).is_err());
+    }
+
+    #[test]
+    pub fn test_cli_only() {
+        let dir = test_workspace("config-cli");
+        let cli = Cli::try_parse_from(["learn-db", "-w", dir.as_str(), "--durability", "never"]).unwrap();
+        let app_config = AppConfig::load(&cli).unwrap();
+        assert_eq!(app_config.dbs.len(), 1);
+        assert_eq!(app_config.dbs[0].workspace, *dir);
+        assert_eq!(app_config.dbs[0].durability, Durability::Never);
+
+        assert!(Cli::try_parse_from(["learn-db", "--durability", "sometimes"]).is_err());
+    }
+
+    #[test]
+    pub fn test_validate() {
+        let dir = test_workspace("config-validate");
+        for args in [
+            vec!["--max-file-num", "1"],
+            vec!["--durability", "interval:0"],
+            vec!["--write-queue-size", "0"],
+            vec!["--write-fast-fail", "--write-push-timeout-ms", "10"],
+            vec!["--workers", "0"],
+            vec!["--name", "a/b"],
+            vec!["--name", "metrics"],
+            vec!["--name", "admin"],
+            vec!["--max-key-size", "0"],
+            vec!["--max-value-size", "2147483648"],
+            vec!["--max-file-size", "4290000000"],
+        ] {
+            let cli = Cli::try_parse_from(["learn-db", "-w", dir.as_str()].into_iter().chain(args.clone())).unwrap();
+            assert!(AppConfig::load(&cli).is_err(), "{:?}", args);
+        }
+
+        let mut app_config: AppConfig = toml::from_str("[[db]]\nworkspace = \"/not/exists\"\ncreate_if_missing = false").unwrap();
+        assert!(app_config.apply(&Cli::default()).is_ok());
+        assert!(app_config.validate().is_err());
+        assert!(toml::from_str::<AppConfig>("[[db]]\nunknown = 1").is_err());
+
+        // 多个数据库的名称不能重复
+        let mut app_config: AppConfig = toml::from_str(&format!("[[db]]\nname = \"a\"\nworkspace = \"{}\"\n\n[[db]]\nname = \"a\"\nworkspace = \"{}/b\"",
+                                                                 dir, dir)).unwrap();
+        assert!(app_config.apply(&Cli::default()).is_ok());
+        assert!(app_config.validate().unwrap_err().message.contains("重复"));
+
+        // 时间段可以跨过零点，开始和结束不能相同
+        assert!(Cli::try_parse_from(["learn-db", "--compaction-windows", "22:00-22:00"]).is_err());
+        assert!(Cli::try_parse_from(["learn-db", "--compaction-windows", "24:00-01:00"]).is_err());
+        let cli = Cli::try_parse_from(["learn-db", "-w", dir.as_str(), "--compaction-windows", "22:00-06:00,12:00-13:30"]).unwrap();
+        let windows = AppConfig::load(&cli).unwrap().dbs[0].compaction_windows.clone();
+        assert!(windows[0].contains(23 * 60) && windows[0].contains(60) && !windows[0].contains(12 * 60));
+        assert!(windows[1].contains(13 * 60 + 29) && !windows[1].contains(13 * 60 + 30));
+        assert_eq!(windows[1].to_string(), "12:00-13:30");
+    }
+}
@@ -1,6 +1,10 @@
-use std::error::Error;
+use std::fmt;
 use std::string::FromUtf8Error;
-use crate::http_param::DataItem;
+
+use actix_web::{HttpResponse, ResponseError};
+use actix_web::http::{header, StatusCode};
+
+use crate::http_param::View;
 use crate::store::write_consumer::WriteEvent;
 
 
@@ -8,26 +12,167 @@ pub type CustomResult<T> = std::result::Result<T, CustomError>;
 
 #[derive(Debug,PartialEq)]
 pub struct CustomError {
-    pub code: usize,
+    pub kind: ErrorKind,
     pub message: String,
 }
 
-pub fn common_err(msg: String) -> CustomError {
-    CustomError {
-        code: 10000,
-        message: msg,
+// 成功，错误码都不会和它重复
+pub const SUCCESS_CODE: usize = 10000;
+// 返回503时，建议客户端多少秒后重试
+pub const RETRY_AFTER_SECS: u64 = 1;
+
+/// 错误类型，每种类型对应固定的错误码和HTTP状态码，客户端根据错误码区分处理
+/// 错误码一旦发布就不能修改，新增的类型使用新的错误码
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    // 写入队列已满，需要稍后重试
+    Overloaded,
+    // 只读模式不允许写入
+    ReadOnly,
+    // 数据库还在恢复中，暂时不能提供服务
+    NotReady,
+    // 请求参数或者配置不合法
+    InvalidArgument,
+    // 请求体、key或者value超过了配置的上限
+    PayloadTooLarge,
+    // 要查找的数据不存在
+    NotFound,
+    // 和当前的状态冲突，例如工作目录已经被其它进程打开
+    Conflict,
+    // 磁盘上的数据损坏，例如校验失败、文件被截断
+    Corruption,
+    // 读写文件失败
+    Io,
+    // 磁盘格式版本比当前程序支持的新，需要升级程序
+    UnsupportedFormat,
+    // 磁盘格式是旧版本或者上次升级没有完成，需要先执行 upgrade
+    NeedsUpgrade,
+    // 其它内部错误
+    Internal,
+}
+
+impl ErrorKind {
+    pub fn code(&self) -> usize {
+        match self {
+            ErrorKind::Overloaded => 10001,
+            ErrorKind::ReadOnly => 10002,
+            ErrorKind::NotReady => 10003,
+            ErrorKind::InvalidArgument => 10004,
+            ErrorKind::NotFound => 10005,
+            ErrorKind::Conflict => 10006,
+            ErrorKind::Corruption => 10007,
+            ErrorKind::Io => 10008,
+            ErrorKind::Internal => 10009,
+            ErrorKind::PayloadTooLarge => 10010,
+            ErrorKind::UnsupportedFormat => 10011,
+            ErrorKind::NeedsUpgrade => 10012,
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorKind::Overloaded | ErrorKind::NotReady => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorKind::ReadOnly => StatusCode::FORBIDDEN,
+            ErrorKind::InvalidArgument => StatusCode::BAD_REQUEST,
+            ErrorKind::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::Conflict => StatusCode::CONFLICT,
+            ErrorKind::Corruption | ErrorKind::Io | ErrorKind::Internal | ErrorKind::UnsupportedFormat
+            | ErrorKind::NeedsUpgrade => StatusCode::INTERNAL_SERVER_ERROR,
+        }
     }
 }
 
 impl CustomError {
-    pub fn new(e: Box<dyn Error>) -> CustomError {
-        common_err(e.to_string())
+    pub fn new(kind: ErrorKind, message: String) -> CustomError {
+        CustomError { kind, message }
+    }
+
+    pub fn code(&self) -> usize {
+        self.kind.code()
     }
 }
 
+pub fn common_err(msg: String) -> CustomError {
+    CustomError::new(ErrorKind::Internal, msg)
+}
+
+pub fn overloaded_err(msg: String) -> CustomError {
+    CustomError::new(ErrorKind::Overloaded, msg)
+}
+
+pub fn read_only_err(msg: String) -> CustomError {
+    CustomError::new(ErrorKind::ReadOnly, msg)
+}
+
+pub fn not_ready_err(msg: String) -> CustomError {
+    CustomError::new(ErrorKind::NotReady, msg)
+}
+
+pub fn invalid_argument_err(msg: String) -> CustomError {
+    CustomError::new(ErrorKind::InvalidArgument, msg)
+}
+
+pub fn payload_too_large_err(msg: String) -> CustomError {
+    CustomError::new(ErrorKind::PayloadTooLarge, msg)
+}
+
+pub fn not_found_err(msg: String) -> CustomError {
+    CustomError::new(ErrorKind::NotFound, msg)
+}
+
+pub fn conflict_err(msg: String) -> CustomError {
+    CustomError::new(ErrorKind::Conflict, msg)
+}
+
+pub fn corruption_err(msg: String) -> CustomError {
+    CustomError::new(ErrorKind::Corruption, msg)
+}
+
+pub fn unsupported_format_err(msg: String) -> CustomError {
+    CustomError::new(ErrorKind::UnsupportedFormat, msg)
+}
+
+pub fn needs_upgrade_err(msg: String) -> CustomError {
+    CustomError::new(ErrorKind::NeedsUpgrade, msg)
+}
+
+impl fmt::Display for CustomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// 接口直接返回 CustomError，按错误类型转换成对应的HTTP状态码，响应体是带错误码的 View
+impl ResponseError for CustomError {
+    fn status_code(&self) -> StatusCode {
+        self.kind.status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        // key不存在是正常的查询结果，不需要警告
+        match self.kind {
+            _ if status.is_server_error() => log::error!("请求处理失败,{:?}", self),
+            ErrorKind::NotFound => log::debug!("请求处理失败,{:?}", self),
+            _ => log::warn!("请求处理失败,{:?}", self),
+        }
+        let mut res = HttpResponse::build(status);
+        // 稍后重试就可以恢复的错误，提示客户端重试的时间
+        if status == StatusCode::SERVICE_UNAVAILABLE {
+            res.insert_header((header::RETRY_AFTER, RETRY_AFTER_SECS.to_string()));
+        }
+        res.json(View::error(self.code(), &self.message))
+    }
+}
+
+/// 文件被截断或者内容不合法属于数据损坏，其它都是读写失败
 impl From<std::io::Error> for CustomError {
     fn from(e: std::io::Error) -> Self {
-        common_err(e.to_string())
+        match e.kind() {
+            std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::InvalidData => corruption_err(e.to_string()),
+            _ => CustomError::new(ErrorKind::Io, e.to_string()),
+        }
     }
 }
 
@@ -39,7 +184,7 @@ impl From<serde_json::Error> for CustomError {
 
 impl From<FromUtf8Error> for CustomError {
     fn from(e: FromUtf8Error) -> Self {
-        common_err(e.to_string())
+        corruption_err(e.to_string())
     }
 }
 
@@ -47,4 +192,39 @@ impl From<tokio::sync::mpsc::error::SendError<WriteEvent>> for CustomError {
     fn from(e: tokio::sync::mpsc::error::SendError<WriteEvent>) -> Self {
         common_err(e.to_string())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::body::MessageBody;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::ResponseError;
+
+    use crate::custom_err::{CustomError, ErrorKind, not_found_err, overloaded_err, SUCCESS_CODE};
+
+    #[test]
+    pub fn test_error_response() {
+        let res = not_found_err(String::from("key[a]不存在")).error_response();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let body = res.into_body().try_into_bytes().unwrap();
+        assert_eq!(body, r#"{"code":10005,"data":"key[a]不存在"}"#.as_bytes());
+
+        let res = overloaded_err(String::from("写入队列已满")).error_response();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(res.headers().contains_key(header::RETRY_AFTER));
+
+        // 文件被截断属于数据损坏
+        let err: CustomError = std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into();
+        assert_eq!(err.kind, ErrorKind::Corruption);
+
+        // 错误码不能和成功重复，也不能互相重复
+        let kinds = [ErrorKind::Overloaded, ErrorKind::ReadOnly, ErrorKind::NotReady, ErrorKind::InvalidArgument,
+            ErrorKind::NotFound, ErrorKind::Conflict, ErrorKind::Corruption, ErrorKind::Io, ErrorKind::Internal, ErrorKind::PayloadTooLarge,
+            ErrorKind::UnsupportedFormat, ErrorKind::NeedsUpgrade];
+        let mut codes: Vec<usize> = kinds.iter().map(|kind| kind.code()).collect();
+        codes.push(SUCCESS_CODE);
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), kinds.len() + 1);
+    }
+}
@@ -0,0 +1,172 @@
+use std::future::{ready, Ready};
+use std::sync::{Arc, OnceLock};
+
+use actix_web::{FromRequest, HttpRequest, web};
+use actix_web::dev::Payload;
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::custom_err::{common_err, not_ready_err};
+use crate::store::data_manager::DataManager;
+use crate::store::recover_task::{RecoverProgress, RecoverProgressView};
+
+// 写入接口的请求体中，除了key和value以外最多允许的字节数
+const JSON_BODY_OVERHEAD: usize = 1024;
+// JSON转义后最多变成原来的几倍，控制字符会转义成 \u00XX
+const JSON_ESCAPE_RATIO: usize = 6;
+
+/// 单个数据库的句柄
+/// HTTP服务在恢复索引之前就启动，恢复完成后才把 DataManager 放进来，在这之前数据接口返回503
+pub struct DbHandle {
+    pub name: String,
+    // 写入接口请求体的上限
+    pub body_limit: usize,
+    // 索引恢复进度
+    progress: Arc<RecoverProgress>,
+    // 恢复完成后才有值
+    dm: OnceLock<DataManager>,
+}
+
+/// 就绪检查的结果
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    pub name: String,
+    pub ready: bool,
+    // 索引恢复进度
+    pub recover: RecoverProgressView,
+    // 写入任务是否在运行，只读模式不检查时为空
+    pub write_consumer_running: Option<bool>,
+    // 工作目录是否可写，只读模式不检查时为空
+    pub disk_writable: Option<bool>,
+}
+
+impl DbHandle {
+    pub fn new(cnf: &Config) -> DbHandle {
+        DbHandle {
+            name: cnf.name.clone(),
+            // 限制的是转义后的大小，按最坏的转义情况放宽，key和value的准确长度由 DataManager::push 检查
+            body_limit: (cnf.max_key_size + cnf.max_value_size).saturating_mul(JSON_ESCAPE_RATIO)
+                .saturating_add(JSON_BODY_OVERHEAD),
+            progress: Arc::new(RecoverProgress::default()),
+            dm: OnceLock::new(),
+        }
+    }
+
+    pub fn progress(&self) -> Arc<RecoverProgress> {
+        self.progress.clone()
+    }
+
+    /// 还在恢复中时返回None
+    pub fn get(&self) -> Option<&DataManager> {
+        self.dm.get()
+    }
+
+    /// 恢复完成，开始提供服务
+    pub fn set(&self, dm: DataManager) {
+        if self.dm.set(dm).is_err() {
+            log::warn!("数据库[{}]重复打开", self.name);
+        }
+    }
+
+    /// 索引恢复完成、写入任务在运行并且磁盘可写时才算就绪；只读模式只要求恢复完成
+    pub async fn readiness(&self) -> Readiness {
+        let mut readiness = Readiness {
+            name: self.name.clone(),
+            ready: false,
+            recover: self.progress.view(),
+            write_consumer_running: None,
+            disk_writable: None,
+        };
+        let dm = match self.get() {
+            None => return readiness,
+            Some(dm) => dm,
+        };
+        if dm.is_read_only() {
+            readiness.ready = readiness.recover.done;
+            return readiness;
+        }
+        let running = dm.write_consumer_running();
+        let writable = match dm.check_disk_writable().await {
+            Ok(_) => true,
+            Err(e) => {
+                log::error!("数据库[{}]的工作目录不可写,{:?}", self.name, e);
+                false
+            }
+        };
+        readiness.ready = readiness.recover.done && running && writable;
+        readiness.write_consumer_running = Some(running);
+        readiness.disk_writable = Some(writable);
+        readiness
+    }
+}
+
+/// 从请求所在的数据库句柄中取出 DataManager，还在恢复中时返回503
+impl FromRequest for DataManager {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<DataManager, actix_web::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let handle = match req.app_data::<web::Data<DbHandle>>() {
+            Some(handle) => handle,
+            None => return ready(Err(common_err(String::from("没有找到数据库")).into())),
+        };
+        ready(handle.get().cloned().ok_or_else(|| {
+            not_ready_err(format!("数据库[{}]正在恢复索引，请稍后重试", handle.name)).into()
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::db_handle::DbHandle;
+    use crate::http_param::DataItem;
+    use crate::store::data_manager::DataManager;
+    use crate::test_util::test_workspace;
+
+    async fn concurrent_readiness(handle: &DbHandle) -> Vec<bool> {
+        let (a, b, c, d) = tokio::join!(handle.readiness(), handle.readiness(), handle.readiness(), handle.readiness());
+        vec![a.ready, b.ready, c.ready, d.ready]
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_readiness() {
+        let workspace = test_workspace("readiness");
+
+        let cnf = Config::new(workspace.to_string());
+        let handle = DbHandle::new(&cnf);
+        assert!(!handle.readiness().await.ready);
+
+        let dm = DataManager::open(cnf, handle.progress()).await.unwrap();
+        handle.set(dm.clone());
+        let readiness = handle.readiness().await;
+        assert!(readiness.ready);
+        assert!(readiness.recover.done);
+        assert_eq!(readiness.write_consumer_running, Some(true));
+        assert_eq!(readiness.disk_writable, Some(true));
+        // 并发检查时使用不同的探测文件，不会互相删掉
+        let all = concurrent_readiness(&handle).await;
+        assert!(all.iter().all(|ready| *ready));
+
+        // 写入任务结束后不再就绪
+        dm.shutdown().await;
+        let readiness = handle.readiness().await;
+        assert!(!readiness.ready);
+        assert_eq!(readiness.write_consumer_running, Some(false));
+    }
+
+    #[test]
+    fn test_body_limit() {
+        let mut cnf = Config::new(String::from("unused"));
+        cnf.max_key_size = 16;
+        cnf.max_value_size = 1024;
+        let handle = DbHandle::new(&cnf);
+        // 长度正好在上限内的key和value，转义后也不会超过请求体的上限
+        for c in ['"', '\u{1}'] {
+            let item = DataItem { key: c.to_string().repeat(cnf.max_key_size), value: c.to_string().repeat(cnf.max_value_size) };
+            let body = serde_json::to_vec(&item).unwrap();
+            assert!(body.len() > cnf.max_key_size + cnf.max_value_size);
+            assert!(body.len() <= handle.body_limit, "{:?}", c);
+        }
+    }
+}
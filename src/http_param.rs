@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::custom_err::SUCCESS_CODE;
+
 #[derive(Serialize)]
 pub struct View<T> {
     code: u32,
@@ -9,7 +11,14 @@ pub struct View<T> {
 impl<T> View<T> {
     pub fn success(value: T) -> View<T> {
         View {
-            code: 10000,
+            code: SUCCESS_CODE as u32,
+            data: value,
+        }
+    }
+
+    pub fn error(code: usize, value: T) -> View<T> {
+        View {
+            code: code as u32,
             data: value,
         }
     }
@@ -20,3 +29,16 @@ pub struct DataItem {
     pub key: String,
     pub value: String,
 }
+
+/// 写入请求的可选参数，通过url传递，例如 /set_sync?durable=true
+#[derive(Deserialize)]
+pub struct WriteOption {
+    // 覆盖配置的落盘策略，true表示落盘后才算写入完成
+    pub durable: Option<bool>,
+}
+
+/// 修改整理任务的限速，0表示不限速
+#[derive(Deserialize)]
+pub struct RateLimitParam {
+    pub bytes_per_sec: u64,
+}
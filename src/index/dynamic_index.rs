@@ -1,5 +1,7 @@
 use std::cmp::min;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use log::info;
 use tokio::sync::RwLock;
@@ -24,6 +26,10 @@ struct DynamicParallelIndex {
 pub struct DynamicParallelIndexWrapper {
     // 只有扩缩容期间，才会申请写锁，其它不管是读取还是写入数据，都申请读锁，所以不用担心这里的并行度
     inner: Arc<RwLock<DynamicParallelIndex>>,
+    // 每个文件中仍然被索引引用的数据量，用来计算文件中的无效数据
+    live_bytes: Arc<Mutex<HashMap<u32, u64>>>,
+    // 扩容完成的次数
+    resizes: Arc<AtomicU64>,
 }
 
 impl DynamicParallelIndexWrapper {
@@ -32,7 +38,9 @@ impl DynamicParallelIndexWrapper {
             inner: Arc::new(RwLock::new(DynamicParallelIndex {
                 parallel_index: ParallelIndex::new(parallel),
                 new_parallel_index: None,
-            }))
+            })),
+            live_bytes: Arc::new(Mutex::new(HashMap::new())),
+            resizes: Arc::new(AtomicU64::new(0)),
         };
         // 启动自动扩缩容的定时任务
         DynamicParallelIndexWrapper::start_dynamic_capacity(wrapper.clone());
@@ -42,49 +50,77 @@ impl DynamicParallelIndexWrapper {
     pub async fn push(&self, key: &String, dp: DataPosition) {
         let push_function = |inner: Arc<RwLock<DynamicParallelIndex>>, dp: DataPosition| async move {
             let index_gurad = inner.read().await;
-            let success = index_gurad.parallel_index.push(key, dp.clone()).await;
-            if success {
-                return success;
+            let res = index_gurad.parallel_index.push(key, dp.clone()).await;
+            if res.0 {
+                res
             } else {
                 info!("当前index正在扩缩容，原数据已被移动，正在去新索引查找....");
                 // 要释放读锁，不然扩缩容那里无法获取写锁
                 match &index_gurad.new_parallel_index {
                     None => {
                         // 这种情况，扩缩容正好完成，所以找不到新的索引了，直接重试就可以
-                        return false;
+                        (false, None)
                     }
                     Some(p) => {
-                        return p.push(key, dp).await;
+                        p.push(key, dp).await
                     }
                 }
             }
         };
 
-        while !push_function(self.inner.clone(), dp.clone()).await {}
+        loop {
+            let (success, old) = push_function(self.inner.clone(), dp.clone()).await;
+            if success {
+                let mut live_bytes = self.live_bytes.lock().unwrap();
+                *live_bytes.entry(dp.file_id).or_insert(0) += dp.len as u64;
+                if let Some(old) = old {
+                    Self::sub_live_bytes(&mut live_bytes, &old);
+                }
+                return;
+            }
+        }
     }
 
+    #[allow(dead_code)]
     pub async fn del(&self, key: &String) {
         let del_function = |inner: Arc<RwLock<DynamicParallelIndex>>| async move {
             let index_gurad = inner.read().await;
-            let success = index_gurad.parallel_index.del(key).await;
-            if success {
-                return success;
+            let res = index_gurad.parallel_index.del(key).await;
+            if res.0 {
+                res
             } else {
                 info!("当前index正在扩缩容，原数据已被移动，正在去新索引查找....");
                 // 要释放读锁，不然扩缩容那里无法获取写锁
                 match &index_gurad.new_parallel_index {
                     None => {
                         // 这种情况，扩缩容正好完成，所以找不到新的索引了，直接重试就可以
-                        return false;
+                        (false, None)
                     }
                     Some(p) => {
-                        return p.del(key).await;
+                        p.del(key).await
                     }
                 }
             }
         };
 
-        while !del_function(self.inner.clone()).await {}
+        loop {
+            let (success, old) = del_function(self.inner.clone()).await;
+            if success {
+                if let Some(old) = old {
+                    Self::sub_live_bytes(&mut self.live_bytes.lock().unwrap(), &old);
+                }
+                return;
+            }
+        }
+    }
+
+    fn sub_live_bytes(live_bytes: &mut HashMap<u32, u64>, dp: &DataPosition) {
+        if let Some(bytes) = live_bytes.get_mut(&dp.file_id) {
+            *bytes = bytes.saturating_sub(dp.len as u64);
+            if *bytes == 0 {
+                live_bytes.remove(&dp.file_id);
+            }
+        }
     }
 
     pub async fn find(&self, key: &String) -> Option<DataPosition> {
@@ -92,17 +128,17 @@ impl DynamicParallelIndexWrapper {
             let index_gurad = inner.read().await;
             let (success, res) = index_gurad.parallel_index.find(key).await;
             if success {
-                return (success, res);
+                (success, res)
             } else {
                 info!("当前index正在扩缩容，原数据已被移动，正在去新索引查找....");
                 // 要释放读锁，不然扩缩容那里无法获取写锁
                 match &index_gurad.new_parallel_index {
                     None => {
                         // 这种情况，扩缩容正好完成，所以找不到新的索引了，直接重试就可以
-                        return (false, None);
+                        (false, None)
                     }
                     Some(p) => {
-                        return p.find(key).await;
+                        p.find(key).await
                     }
                 }
             }
@@ -120,6 +156,22 @@ impl DynamicParallelIndexWrapper {
         inner.parallel_index.size()
     }
 
+    /// 当前的并行度
+    pub async fn parallel(&self) -> u64 {
+        let inner = self.inner.read().await;
+        inner.parallel_index.get_parallel()
+    }
+
+    /// 每个文件中仍然有效的数据量
+    pub fn live_bytes(&self) -> HashMap<u32, u64> {
+        self.live_bytes.lock().unwrap().clone()
+    }
+
+    /// 扩容完成的次数
+    pub fn resize_count(&self) -> u64 {
+        self.resizes.load(Ordering::Relaxed)
+    }
+
     /// 定时任务，检查是否需要扩缩容
     pub fn start_dynamic_capacity(wrapper_clone: DynamicParallelIndexWrapper) {
         // let wrapper_clone = wrapper.clone();
@@ -133,7 +185,7 @@ impl DynamicParallelIndexWrapper {
                 // 返回true，表示需要调整容量
                 if wrapper_clone.dynamic_capacity_check().await {
                     //todo 根据cpu设置
-                    let thread_size = 8 as u64;
+                    let thread_size = 8_u64;
                     let mut threads = Vec::new();
 
 
@@ -171,6 +223,7 @@ impl DynamicParallelIndexWrapper {
 
                     let mut mut_inner = wrapper_clone.inner.write().await;
                     mut_inner.parallel_index = mut_inner.new_parallel_index.take().unwrap();
+                    wrapper_clone.resizes.fetch_add(1, Ordering::Relaxed);
 
                     info!("扩容完成！");
                 }
@@ -207,6 +260,7 @@ impl DynamicParallelIndexWrapper {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
     use std::sync::atomic::{AtomicBool, Ordering};
 
     use crate::index::DataPosition;
@@ -216,19 +270,28 @@ mod tests {
     #[tokio::test]
     async fn test_new() {
         let index = DynamicParallelIndexWrapper::new(8);
-        init_log();
+        init_log("log4rs.yaml");
 
         for i in 0..1024 {
-            index.push(&i.to_string(), DataPosition::new(i as u32, i as u32, i as u32)).await;
+            index.push(&i.to_string(), DataPosition::new(i as u32, i as u32)).await;
         }
         assert_eq!(index.size().await, 1024);
 
-        assert_eq!(index.find(&String::from("1")).await, Some(DataPosition::new(1, 1, 1)));
-        assert_eq!(index.find(&String::from("8")).await, Some(DataPosition::new(8, 8, 8)));
+        assert_eq!(index.find(&String::from("1")).await, Some(DataPosition::new(1, 1)));
+        assert_eq!(index.find(&String::from("8")).await, Some(DataPosition::new(8, 8)));
         assert_eq!(index.find(&String::from("80000")).await, None);
 
         index.del(&String::from("8")).await;
         assert_eq!(index.find(&String::from("8")).await, None);
+
+        // 覆盖和删除时，旧位置的数据量要从对应的文件中扣掉
+        let index = DynamicParallelIndexWrapper::new(8);
+        index.push(&String::from("a"), DataPosition::new(1, 0).with_len(10)).await;
+        index.push(&String::from("b"), DataPosition::new(1, 10).with_len(20)).await;
+        index.push(&String::from("a"), DataPosition::new(2, 0).with_len(15)).await;
+        assert_eq!(index.live_bytes(), HashMap::from([(1, 20), (2, 15)]));
+        index.del(&String::from("b")).await;
+        assert_eq!(index.live_bytes(), HashMap::from([(2, 15)]));
         //  std::thread::sleep(std::time::Duration::from_secs(10));
     }
 
@@ -16,14 +16,13 @@ impl LinkedHashSet {
     }
 
     /// 插入或更新，如果存在相同的，更新，不存在则插入
-    /// 返回 1 表示插入 0 表示更新
-    pub fn push(&mut self, key: &String, dp: DataPosition) -> u8 {
+    /// 更新时返回旧的位置，插入时返回None
+    pub fn push(&mut self, key: &String, dp: DataPosition) -> Option<DataPosition> {
         let mut node = &mut self.head;
         // 如果找到，就更新
         while let Some(v) = node {
             if v.key == *key {
-                v.update_dp(dp);
-                return 0;
+                return Some(v.update_dp(dp));
             }
             node = &mut v.next;
         }
@@ -34,7 +33,7 @@ impl LinkedHashSet {
             next: self.head.take(),
         };
         self.head = Some(Box::new(head));
-        1
+        None
     }
 
     /// 移除第一个节点
@@ -61,26 +60,29 @@ impl LinkedHashSet {
         None
     }
 
-    /// 根据hash删除指定节点
-    pub fn del(&mut self, key: &String) -> u8 {
+    /// 根据hash删除指定节点，返回被删除节点的位置
+    #[allow(dead_code)]
+    pub fn del(&mut self, key: &String) -> Option<DataPosition> {
         let mut node = &mut self.head;
         if let Some(v) = node {
             if v.key == *key {
+                let dp = v.dp.clone();
                 self.head = v.next.take();
-                return 1;
+                return Some(dp);
             }
         }
 
         while let Some(v) = node {
             if let Some(next) = &mut v.next {
                 if next.key == *key {
+                    let dp = next.dp.clone();
                     v.next = next.next.take();
-                    return 1;
+                    return Some(dp);
                 }
             }
             node = &mut v.next;
         }
-        0
+        None
     }
 
     pub fn is_moved(&self) -> bool {
@@ -101,11 +103,11 @@ mod tests {
     #[test]
     pub fn test_linked_hash_set() {
         let mut hash_set = LinkedHashSet::new();
-        hash_set.push(&String::from("1"), DataPosition::new(1, 2, 3));
-        hash_set.push(&String::from("2"), DataPosition::new(1, 2, 3));
-        hash_set.push(&String::from("1"), DataPosition::new(1, 3, 3));
-        hash_set.push(&String::from("3"), DataPosition::new(1, 2, 3));
-        assert_eq!(hash_set.find(&String::from("1")), Some(DataPosition::new(1, 3, 3)));
+        hash_set.push(&String::from("1"), DataPosition::new(1, 2));
+        hash_set.push(&String::from("2"), DataPosition::new(1, 2));
+        hash_set.push(&String::from("1"), DataPosition::new(1, 3));
+        hash_set.push(&String::from("3"), DataPosition::new(1, 2));
+        assert_eq!(hash_set.find(&String::from("1")), Some(DataPosition::new(1, 3)));
         hash_set.del(&String::from("2"));
         assert_eq!(hash_set.find(&String::from("2")), None);
         println!("hash_set:{:?}", hash_set)
@@ -2,15 +2,20 @@ mod linked_hash_set;
 mod parallel_index;
 pub mod dynamic_index;
 
+use std::hash::{Hash, Hasher};
+
 use serde::{Deserialize, Serialize};
 
 /// 数据的位置
-#[derive(Debug, Clone, PartialEq,Serialize, Deserialize)]
+/// 文件id和偏移量就能唯一确定一条数据，比较和hash时不考虑长度
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DataPosition {
     // 文件id
     pub file_id: u32,
     // 偏移量
     pub offset: u32,
+    // 记录的长度，只用于统计每个文件的有效数据量
+    pub len: u32,
 }
 
 impl DataPosition {
@@ -18,8 +23,29 @@ impl DataPosition {
         DataPosition {
             file_id,
             offset,
+            len: 0,
         }
     }
+
+    pub fn with_len(mut self, len: u32) -> Self {
+        self.len = len;
+        self
+    }
+}
+
+impl PartialEq for DataPosition {
+    fn eq(&self, other: &Self) -> bool {
+        self.file_id == other.file_id && self.offset == other.offset
+    }
+}
+
+impl Eq for DataPosition {}
+
+impl Hash for DataPosition {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.file_id.hash(state);
+        self.offset.hash(state);
+    }
 }
 
 type Link = Option<Box<Node>>;
@@ -33,8 +59,9 @@ pub struct Node {
 }
 
 impl Node {
-    pub fn update_dp(&mut self, dp: DataPosition) {
-        self.dp = dp;
+    /// 更新位置，返回旧的位置
+    pub fn update_dp(&mut self, dp: DataPosition) -> DataPosition {
+        std::mem::replace(&mut self.dp, dp)
     }
 }
 
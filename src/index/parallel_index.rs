@@ -32,17 +32,20 @@ impl ParallelIndex {
 
 
     /// 插入数据，
-    /// 当插入成功时，返回true，
-    /// 如果底层的linked_hash_set被移动，导致无法插入，返回false
-    pub async fn push(&self, key: &String, dp: DataPosition) -> bool {
+    /// 第一个返回值表示是否插入成功，如果底层的linked_hash_set被移动，导致无法插入，返回false
+    /// 第二个返回值是被覆盖的旧位置
+    pub async fn push(&self, key: &String, dp: DataPosition) -> (bool, Option<DataPosition>) {
         let hash = calc_hash(key);
         let vec_i = hash % self.parallel;
         let mut set = self.get_link(vec_i).write().await;
         if set.is_moved() {
-            return false;
+            return (false, None);
+        }
+        let old = set.push(key, dp);
+        if old.is_none() {
+            self.size.fetch_add(1, Ordering::SeqCst);
         }
-        self.size.fetch_add(set.push(key, dp) as u64, Ordering::SeqCst);
-        true
+        (true, old)
     }
 
     /// 查找数据
@@ -57,16 +60,21 @@ impl ParallelIndex {
         (true, set.find(key))
     }
 
-    pub async fn del(&self, key: &String) -> bool {
+    /// 删除数据，返回值和push一样
+    #[allow(dead_code)]
+    pub async fn del(&self, key: &String) -> (bool, Option<DataPosition>) {
         let hash = calc_hash(key);
         let vec_i = hash % self.parallel;
         let mut set = self.get_link(vec_i).write().await;
         if set.is_moved() {
-            return false;
+            return (false, None);
         }
 
-        self.size.fetch_sub(set.del(key) as u64, Ordering::SeqCst);
-        true
+        let old = set.del(key);
+        if old.is_some() {
+            self.size.fetch_sub(1, Ordering::SeqCst);
+        }
+        (true, old)
     }
 
     pub fn size(&self) -> u64 {
@@ -97,13 +105,13 @@ mod tests {
             .unwrap();
         rt.block_on(async {
             let index = ParallelIndex::new(8);
-            assert_eq!(index.push(&String::from("1"), DataPosition::new(1, 1, 1)).await, true);
-            assert_eq!(index.push(&String::from("2"), DataPosition::new(2, 2, 2)).await, true);
-            assert_eq!(index.push(&String::from("1"), DataPosition::new(3, 3, 3)).await, true);
-            assert_eq!(index.push(&String::from("3"), DataPosition::new(4, 4, 4)).await, true);
+            assert_eq!(index.push(&String::from("1"), DataPosition::new(1, 1)).await, (true, None));
+            assert!(index.push(&String::from("2"), DataPosition::new(2, 2)).await.0);
+            assert_eq!(index.push(&String::from("1"), DataPosition::new(3, 3)).await, (true, Some(DataPosition::new(1, 1))));
+            assert!(index.push(&String::from("3"), DataPosition::new(4, 4)).await.0);
 
-            assert_eq!(index.find(&String::from("1")).await, (true, Some(DataPosition::new(3, 3, 3))));
-            assert_eq!(index.del(&String::from("3")).await, true);
+            assert_eq!(index.find(&String::from("1")).await, (true, Some(DataPosition::new(3, 3))));
+            assert!(index.del(&String::from("3")).await.0);
             assert_eq!(index.find(&String::from("3")).await, (true, None));
 
             assert_eq!(index.size(), 2);
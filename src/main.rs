@@ -1,69 +1,200 @@
-#[macro_use]
-extern crate lazy_static;
-
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
-use std::path::{Path, PathBuf};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
 
-use actix_web::{App, HttpResponse, HttpServer, Responder, web};
-use log::info;
+use actix_web::{App, HttpResponse, HttpServer, Responder, Scope, web};
+use actix_web::dev::Service;
+use actix_web::error::JsonPayloadError;
+use actix_web::http::header;
+use clap::Parser;
+use log::LevelFilter;
+use log4rs::append::console::ConsoleAppender;
+use log4rs::config::{Appender, Root};
+use log4rs::encode::pattern::PatternEncoder;
 use tokio::sync::oneshot;
 
-use crate::http_param::{DataItem, View};
-use crate::index::DataPosition;
-use crate::index::dynamic_index::DynamicParallelIndexWrapper;
+use crate::config::{AppConfig, Cli};
+use crate::db_handle::DbHandle;
+use crate::custom_err::{common_err, CustomError, CustomResult, ErrorKind, invalid_argument_err, not_found_err, payload_too_large_err,
+                        RETRY_AFTER_SECS};
+use crate::http_param::{DataItem, RateLimitParam, View, WriteOption};
+use crate::metrics::HttpMetrics;
 use crate::store::data_manager::DataManager;
 use crate::store::write_consumer::WriteEvent;
 
 mod index;
+mod config;
 mod custom_err;
+mod db_handle;
 mod http_param;
+mod metrics;
 mod store;
+mod tools;
+#[cfg(test)]
+mod test_util;
 
-#[derive(Clone)]
-pub struct Config {
-    // 数据文件存储的目录
-    pub workspace: String,
-    // 单个数据文件最大值，超过后就会写新文件
-    pub max_file_size: u32,
-    // 最多允许的数据文件个数，超过后就会开始整理合并
-    pub max_file_num: u32,
-}
-
-impl Config {
-    pub fn new(workspace: String) -> Config {
-        Config {
-            workspace,
-            max_file_size: 1024 * 1024 * 1024,
-            max_file_num: 10,
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let mut cli = Cli::parse();
+    if let Some(command) = cli.command.take() {
+        match tools::run(command).await {
+            Ok(0) => return Ok(()),
+            Ok(code) => std::process::exit(code),
+            Err(e) => {
+                eprintln!("{}", e.message);
+                std::process::exit(exit_code(&e));
+            }
         }
     }
-}
 
+    let app_config = match AppConfig::load(&cli) {
+        Ok(app_config) => app_config,
+        Err(e) => {
+            eprintln!("{}", e.message);
+            std::process::exit(2);
+        }
+    };
+    init_log(&app_config.server.log_config);
+    log::info!("配置:{:?}", app_config);
+
+    let server = app_config.server;
+    // 挂在根路径的数据库放到最后，不然会把其它数据库的请求拦截掉
+    let mut configs = app_config.dbs;
+    configs.sort_by_key(|config| config.name.is_empty());
+    let handles: Vec<Arc<DbHandle>> = configs.iter().map(|config| Arc::new(DbHandle::new(config))).collect();
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    init_log();
+    // 先启动HTTP服务，恢复索引期间 /health 和 /ready 就可以访问
+    let server_dbs = web::Data::new(handles.clone());
+    let http_metrics = web::Data::new(HttpMetrics::default());
+    // 不使用 actix 自带的信号处理，它收到 SIGINT 时会直接停止，不等待处理中的请求
+    let http_server = HttpServer::new(move || {
+        let request_metrics = http_metrics.clone();
+        let mut app = App::new()
+            .app_data(server_dbs.clone())
+            .app_data(web::QueryConfig::default().error_handler(|err, _| invalid_param(err)))
+            .app_data(web::PathConfig::default().error_handler(|err, _| invalid_param(err)))
+            .app_data(http_metrics.clone())
+            // 统计每个路由的请求数和耗时
+            .wrap_fn(move |req, srv| {
+                let metrics = request_metrics.clone();
+                let method = req.method().to_string();
+                let start = Instant::now();
+                let fut = srv.call(req);
+                async move {
+                    let res = fut.await?;
+                    metrics.observe(res.request().match_pattern(), &method, res.status().as_u16(),
+                                    start.elapsed().as_micros() as u64);
+                    Ok(res)
+                }
+            })
+            .service(hello)
+            .service(health)
+            .service(ready_all)
+            .service(prometheus_metrics);
+        for handle in server_dbs.iter() {
+            app = app.service(db_scope(handle.clone()));
+        }
+        app
+    })
+        .workers(server.workers)
+        .disable_signals()
+        .bind((server.host.as_str(), server.port))?
+        .run();
+    let server_handle = http_server.handle();
+    let http_server = actix_web::rt::spawn(http_server);
+    let signal_handle = server_handle.clone();
+    actix_web::rt::spawn(async move {
+        wait_shutdown_signal().await;
+        log::info!("收到关闭信号，等待处理中的请求完成");
+        signal_handle.stop(true).await;
+    });
 
-    let config = Config::new(String::from("/Users/yang/logs/learn-db"));
+    for (config, handle) in configs.into_iter().zip(&handles) {
+        match DataManager::open(config, handle.progress()).await {
+            Ok(dm) => handle.set(dm),
+            Err(e) => {
+                log::error!("打开数据库[{}]失败,{}", handle.name, e.message);
+                server_handle.stop(false).await;
+                shutdown_dbs(&handles).await;
+                std::process::exit(exit_code(&e));
+            }
+        }
+    }
+    log::info!("全部数据库已经打开");
 
-    if !Path::new(&config.workspace).is_dir() {
-        panic!("workspace[{}] 不合法,必须存在且是文件夹！", config.workspace);
+    if let Ok(res) = http_server.await {
+        res?;
     }
+    // 把已经接收的写入全部落盘后再退出
+    shutdown_dbs(&handles).await;
+    Ok(())
+}
 
-    let dm = DataManager::new(config).await;
+/// 等待 SIGTERM 或者 SIGINT，收到后停止接收新请求，等待处理中的请求完成后HTTP服务才返回
+async fn wait_shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
 
-    HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(dm.clone()))
-            .service(hello)
-            .service(find)
-            .service(push)
-            .service(push_sync)
-    })
-        .bind(("127.0.0.1", 8848))?
-        .run()
-        .await
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(e) => {
+                log::error!("注册 SIGTERM 处理失败,{}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// 出错退出时的退出码，磁盘格式不匹配时单独区分，脚本据此判断是要升级程序还是要先执行 upgrade
+fn exit_code(e: &CustomError) -> i32 {
+    match e.kind {
+        ErrorKind::UnsupportedFormat => 3,
+        ErrorKind::NeedsUpgrade => 4,
+        _ => 2,
+    }
+}
+
+/// 关闭已经打开的数据库
+async fn shutdown_dbs(handles: &[Arc<DbHandle>]) {
+    for handle in handles {
+        if let Some(dm) = handle.get() {
+            dm.shutdown().await;
+        }
+    }
+}
+
+/// 单个数据库的全部接口
+fn db_scope(handle: Arc<DbHandle>) -> Scope {
+    let path = if handle.name.is_empty() { String::new() } else { format!("/{}", handle.name) };
+    web::scope(&path)
+        // 请求体的上限按配置的key和value的最大长度计算
+        .app_data(web::JsonConfig::default().limit(handle.body_limit).error_handler(|err, _| json_error(err)))
+        .app_data(web::Data::from(handle))
+        .service(ready)
+        .service(stats)
+        .service(admin_stats)
+        .service(admin_key)
+        .service(admin_compact)
+        .service(admin_generate_index)
+        .service(admin_rotate)
+        .service(admin_pause_compaction)
+        .service(admin_resume_compaction)
+        .service(admin_compaction_rate_limit)
+        .service(admin_jobs)
+        .service(admin_job)
+        .service(find)
+        .service(push)
+        .service(push_sync)
 }
 
 
@@ -72,38 +203,197 @@ async fn hello() -> impl Responder {
     HttpResponse::Ok().body("Welcome to Learn-DB!")
 }
 
+/// 存活检查，进程在运行就返回200，恢复索引期间也一样
+#[actix_web::get("/health")]
+async fn health() -> impl Responder {
+    web::Json(View::success("ok"))
+}
+
+/// 全部数据库都就绪时返回200，否则返回503，并带上每个数据库的检查结果
+#[actix_web::get("/ready")]
+async fn ready_all(dbs: web::Data<Vec<Arc<DbHandle>>>) -> HttpResponse {
+    let mut all = Vec::with_capacity(dbs.len());
+    for handle in dbs.iter() {
+        all.push(handle.readiness().await);
+    }
+    if all.iter().all(|readiness| readiness.ready) {
+        HttpResponse::Ok().json(View::success(all))
+    } else {
+        not_ready(all)
+    }
+}
+
+/// prometheus 格式的指标，包括全部已经打开的数据库
+#[actix_web::get("/metrics")]
+async fn prometheus_metrics(dbs: web::Data<Vec<Arc<DbHandle>>>, http: web::Data<HttpMetrics>) -> impl Responder {
+    let opened: Vec<(String, DataManager)> = dbs.iter()
+        .filter_map(|handle| handle.get().map(|dm| (handle.name.clone(), dm.clone())))
+        .collect();
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render(&opened, &http).await)
+}
+
+/// 单个数据库的就绪检查：索引恢复完成、写入任务在运行、磁盘可写，否则返回503
+#[actix_web::get("/ready")]
+async fn ready(handle: web::Data<DbHandle>) -> HttpResponse {
+    let readiness = handle.readiness().await;
+    if readiness.ready {
+        HttpResponse::Ok().json(View::success(readiness))
+    } else {
+        not_ready(readiness)
+    }
+}
+
+/// 未就绪时的503，和其它错误一样返回对应的错误码，检查结果放在 data 中
+fn not_ready<T: serde::Serialize>(readiness: T) -> HttpResponse {
+    HttpResponse::ServiceUnavailable()
+        .insert_header((header::RETRY_AFTER, RETRY_AFTER_SECS.to_string()))
+        .json(View::error(ErrorKind::NotReady.code(), readiness))
+}
+
+/// 读缓存的命中统计、打开的文件句柄数
+#[actix_web::get("/stats")]
+async fn stats(dm: DataManager) -> impl Responder {
+    web::Json(View::success(dm.store_stats()))
+}
+
+/// 索引、数据文件、写入位置和整理任务的状态
+#[actix_web::get("/admin/stats")]
+async fn admin_stats(dm: DataManager) -> impl Responder {
+    web::Json(View::success(dm.admin_stats().await))
+}
+
+/// key在磁盘上的位置和记录的元信息，key不存在时返回404
+#[actix_web::get("/admin/key/{key}")]
+async fn admin_key(key: web::Path<String>, dm: DataManager) -> Result<HttpResponse, CustomError> {
+    let key = key.into_inner();
+    match dm.inspect_key(&key).await? {
+        Some(info) => Ok(HttpResponse::Ok().json(View::success(info))),
+        None => Err(not_found_err(format!("key[{}]不存在", key))),
+    }
+}
+
+/// 马上执行一轮整理
+#[actix_web::post("/admin/compact")]
+async fn admin_compact(dm: DataManager) -> Result<HttpResponse, CustomError> {
+    job_response(&dm, dm.trigger_compaction())
+}
+
+/// 马上为已封存的数据文件生成缺少的索引文件
+#[actix_web::post("/admin/index")]
+async fn admin_generate_index(dm: DataManager) -> Result<HttpResponse, CustomError> {
+    job_response(&dm, dm.trigger_index_generation())
+}
+
+/// 马上切换到新的数据文件
+#[actix_web::post("/admin/rotate")]
+async fn admin_rotate(dm: DataManager) -> Result<HttpResponse, CustomError> {
+    job_response(&dm, dm.trigger_rotation())
+}
+
+/// 暂停后台的定时整理
+#[actix_web::post("/admin/compaction/pause")]
+async fn admin_pause_compaction(dm: DataManager) -> Result<HttpResponse, CustomError> {
+    job_response(&dm, dm.pause_compaction())
+}
+
+/// 恢复后台的定时整理
+#[actix_web::post("/admin/compaction/resume")]
+async fn admin_resume_compaction(dm: DataManager) -> Result<HttpResponse, CustomError> {
+    job_response(&dm, dm.resume_compaction())
+}
+
+/// 运行时修改整理任务的限速，例如 {"bytes_per_sec": 10485760}
+#[actix_web::post("/admin/compaction/rate_limit")]
+async fn admin_compaction_rate_limit(param: web::Json<RateLimitParam>, dm: DataManager) -> Result<HttpResponse, CustomError> {
+    dm.set_compaction_rate_limit(param.bytes_per_sec)?;
+    Ok(HttpResponse::Ok().json(View::success(param.bytes_per_sec)))
+}
+
+/// 最近手动触发的任务，最新的在前面
+#[actix_web::get("/admin/jobs")]
+async fn admin_jobs(dm: DataManager) -> impl Responder {
+    web::Json(View::success(dm.jobs().list()))
+}
+
+/// 查询任务的状态，任务不存在时返回404
+#[actix_web::get("/admin/jobs/{id}")]
+async fn admin_job(id: web::Path<u64>, dm: DataManager) -> Result<HttpResponse, CustomError> {
+    let id = id.into_inner();
+    match dm.job(id) {
+        Some(job) => Ok(HttpResponse::Ok().json(View::success(job))),
+        None => Err(not_found_err(format!("任务[{}]不存在", id))),
+    }
+}
+
+/// 任务提交成功时返回202和任务的状态，可以用任务id查询执行结果
+fn job_response(dm: &DataManager, res: CustomResult<u64>) -> Result<HttpResponse, CustomError> {
+    let id = res?;
+    Ok(HttpResponse::Accepted().json(View::success(dm.job(id))))
+}
+
+/// 读取key对应的value，key不存在时返回404，数据文件丢失或者损坏时返回500
 #[actix_web::get("/get/{key}")]
-async fn find(key: web::Path<String>, dm: web::Data<DataManager>) -> impl Responder {
+async fn find(key: web::Path<String>, dm: DataManager) -> Result<HttpResponse, CustomError> {
     let key = key.into_inner();
-    let res = dm.find(&key).await;
-    info!("url=/get/{},value={:?}", &key, res);
-    web::Json(View::success(res))
+    match dm.find(&key).await? {
+        Some(value) => Ok(HttpResponse::Ok().json(View::success(value))),
+        None => Err(not_found_err(format!("key[{}]不存在", key))),
+    }
 }
 
 #[actix_web::post("/set")]
-async fn push(param: web::Json<DataItem>, dm: web::Data<DataManager>) -> impl Responder {
+async fn push(param: web::Json<DataItem>, option: web::Query<WriteOption>, dm: DataManager) -> Result<HttpResponse, CustomError> {
     let param = param.into_inner();
-    dm.push(WriteEvent::new_simple_event(param)).await;
-    web::Json(View::success(""))
+    dm.push(WriteEvent::new_simple_event(param).with_durable(option.durable)).await?;
+    Ok(HttpResponse::Ok().json(View::success("")))
 }
 
 #[actix_web::post("/set_sync")]
-async fn push_sync(param: web::Json<DataItem>, dm: web::Data<DataManager>) -> impl Responder {
+async fn push_sync(param: web::Json<DataItem>, option: web::Query<WriteOption>, dm: DataManager) -> Result<HttpResponse, CustomError> {
     let param = param.into_inner();
     let (tx, rx) = oneshot::channel();
-    dm.push(WriteEvent::new_callback_event(param, None, tx)).await;
-    // 等待写入完成
-    rx.await.unwrap();
-    web::Json(View::success(""))
-}
-
-pub fn init_log() {
-    let mut config_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-    config_path.push("log4rs.yaml");
-    println!("{:?}", config_path);
-    // Path::new("log4rs.yaml").metadata()?.
-    log4rs::init_file(config_path, Default::default()).unwrap();
-    log::info!("日志初始化成功！");
+    dm.push(WriteEvent::new_callback_event(param, None, tx).with_durable(option.durable)).await?;
+    // 等待写入完成，需要落盘时等待落盘完成
+    rx.await.map_err(|_| common_err(String::from("写入失败，没有收到写入完成的回执")))?;
+    Ok(HttpResponse::Ok().json(View::success("")))
+}
+
+/// 请求参数解析失败时返回400，和其它错误的格式一致
+fn invalid_param(err: impl std::fmt::Display) -> actix_web::Error {
+    invalid_argument_err(format!("请求参数不合法,{}", err)).into()
+}
+
+/// 请求体超过上限时返回413，其它解析错误返回400
+fn json_error(err: JsonPayloadError) -> actix_web::Error {
+    match err {
+        JsonPayloadError::OverflowKnownLength { .. } | JsonPayloadError::Overflow { .. } =>
+            payload_too_large_err(format!("请求体太大,{}", err)).into(),
+        _ => invalid_param(err),
+    }
+}
+
+/// 初始化日志，配置文件不存在时输出到控制台
+pub fn init_log(config_path: &str) {
+    // 测试中会多次调用，重复初始化时忽略
+    if Path::new(config_path).is_file() {
+        if log4rs::init_file(config_path, Default::default()).is_ok() {
+            log::info!("日志初始化成功:{}", config_path);
+        }
+        return;
+    }
+    let stdout = ConsoleAppender::builder()
+        .encoder(Box::new(PatternEncoder::new("{d(%Y-%m-%d %H:%M:%S)} {h({l})} [{M}] [{L}]- {m}{n}")))
+        .build();
+    let config = log4rs::Config::builder()
+        .appender(Appender::builder().build("stdout", Box::new(stdout)))
+        .build(Root::builder().appender("stdout").build(LevelFilter::Info));
+    if let Ok(config) = config {
+        if log4rs::init_config(config).is_ok() {
+            log::info!("日志配置文件[{}]不存在，输出到控制台", config_path);
+        }
+    }
 }
 
 /// 计算hash
@@ -0,0 +1,274 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+
+use crate::store::data_manager::{DataManager, FileStats, StoreStats};
+use crate::store::write_consumer::LATENCY_BOUNDS_US;
+
+// 没有匹配到任何路由的请求统一记到这个路由下，避免路径太多
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// 直方图，统计落在每个区间内的次数
+/// bounds 是每个区间的上限（包含），超过最后一个上限的计入最后的 +Inf 区间
+pub struct Histogram {
+    bounds: Vec<u64>,
+    // 比 bounds 多一个，最后一个是 +Inf
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+/// 直方图的快照，buckets 是累计值，和 prometheus 的格式一致
+#[derive(Serialize, Debug)]
+pub struct HistogramView {
+    pub buckets: Vec<(u64, u64)>,
+    pub sum: u64,
+    pub count: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[u64]) -> Histogram {
+        Histogram {
+            bounds: bounds.to_vec(),
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: u64) {
+        let i = self.bounds.iter().position(|b| value <= *b).unwrap_or(self.bounds.len());
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn view(&self) -> HistogramView {
+        let mut total = 0;
+        let mut buckets = Vec::with_capacity(self.bounds.len());
+        for (i, bound) in self.bounds.iter().enumerate() {
+            total += self.buckets[i].load(Ordering::Relaxed);
+            buckets.push((*bound, total));
+        }
+        HistogramView {
+            buckets,
+            sum: self.sum.load(Ordering::Relaxed),
+            count: self.count.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// HTTP请求的统计信息，按路由模板统计，不按实际的路径，避免key太多
+#[derive(Default)]
+pub struct HttpMetrics {
+    routes: Mutex<BTreeMap<String, RouteMetrics>>,
+}
+
+struct RouteMetrics {
+    // (method, status) 对应的请求数
+    requests: BTreeMap<(String, u16), u64>,
+    // 处理耗时，单位微秒
+    latency: Histogram,
+}
+
+impl HttpMetrics {
+    pub fn observe(&self, route: Option<String>, method: &str, status: u16, elapsed_us: u64) {
+        let route = route.unwrap_or_else(|| String::from(UNMATCHED_ROUTE));
+        let mut routes = self.routes.lock().unwrap();
+        let metrics = routes.entry(route).or_insert_with(|| RouteMetrics {
+            requests: BTreeMap::new(),
+            latency: Histogram::new(&LATENCY_BOUNDS_US),
+        });
+        *metrics.requests.entry((method.to_string(), status)).or_insert(0) += 1;
+        metrics.latency.observe(elapsed_us);
+    }
+}
+
+/// prometheus 文本格式的输出
+/// 同一个指标的说明只输出一次，所以要按指标输出，不能按数据库输出
+#[derive(Default)]
+pub struct PromWriter {
+    out: String,
+}
+
+impl PromWriter {
+    /// 输出指标的说明和类型
+    pub fn header(&mut self, name: &str, help: &str, kind: &str) {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        let _ = writeln!(self.out, "{}{} {}", name, format_labels(labels), value);
+    }
+
+    /// 输出直方图，每个值都除以 unit 来换算单位，比如微秒换算成秒时是 1e6
+    pub fn histogram(&mut self, name: &str, labels: &[(&str, &str)], view: &HistogramView, unit: f64) {
+        for (bound, count) in &view.buckets {
+            let le = (*bound as f64 / unit).to_string();
+            let mut bucket_labels = labels.to_vec();
+            bucket_labels.push(("le", &le));
+            self.sample(&format!("{}_bucket", name), &bucket_labels, count);
+        }
+        let mut bucket_labels = labels.to_vec();
+        bucket_labels.push(("le", "+Inf"));
+        self.sample(&format!("{}_bucket", name), &bucket_labels, view.count);
+        self.sample(&format!("{}_sum", name), labels, view.sum as f64 / unit);
+        self.sample(&format!("{}_count", name), labels, view.count);
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels.iter().map(|(k, v)| {
+        let v = v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+        format!("{}=\"{}\"", k, v)
+    }).collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+// 指标名、说明、类型、取值的方法
+type ValueMetric<T> = (&'static str, &'static str, &'static str, fn(&T) -> u64);
+// 指标名、说明、单位换算的除数、取值的方法
+type HistogramMetric = (&'static str, &'static str, f64, fn(&StoreStats) -> &HistogramView);
+
+/// 单个数据库的指标快照
+struct DbSnapshot<'a> {
+    name: &'a str,
+    stats: StoreStats,
+    index_keys: u64,
+    index_parallel: u64,
+    index_resizes: u64,
+    compaction_runs: u64,
+    compaction_files: u64,
+    compaction_bytes: u64,
+    files: Vec<FileStats>,
+}
+
+/// 输出全部数据库和HTTP请求的指标
+pub async fn render(dbs: &[(String, DataManager)], http: &HttpMetrics) -> String {
+    // 先取出每个数据库的统计，再按指标输出
+    let mut snapshots = Vec::with_capacity(dbs.len());
+    for (name, dm) in dbs {
+        let index = dm.index();
+        let compaction = dm.compaction_metrics();
+        snapshots.push(DbSnapshot {
+            name,
+            stats: dm.store_stats(),
+            index_keys: index.size().await,
+            index_parallel: index.parallel().await,
+            index_resizes: index.resize_count(),
+            compaction_runs: compaction.runs.load(Ordering::Relaxed),
+            compaction_files: compaction.files_reclaimed.load(Ordering::Relaxed),
+            compaction_bytes: compaction.bytes_reclaimed.load(Ordering::Relaxed),
+            files: dm.file_stats(),
+        });
+    }
+
+    let mut w = PromWriter::default();
+    let values: [ValueMetric<DbSnapshot>; 11] = [
+        ("learn_db_write_queue_depth", "写入队列中还没有处理的数据条数", "gauge", |s| s.stats.write_queue_depth as u64),
+        ("learn_db_write_queue_capacity", "写入队列的长度", "gauge", |s| s.stats.write_queue_capacity as u64),
+        ("learn_db_write_rejected_total", "因为队列满被拒绝的写入次数", "counter", |s| s.stats.write_rejected),
+        ("learn_db_read_errors_total", "读取数据文件失败的次数，不包括key不存在", "counter", |s| s.stats.read_errors),
+        ("learn_db_index_keys", "索引中的key数量", "gauge", |s| s.index_keys),
+        ("learn_db_index_parallel", "索引的并行度", "gauge", |s| s.index_parallel),
+        ("learn_db_index_resizes_total", "索引扩容的次数", "counter", |s| s.index_resizes),
+        ("learn_db_open_file_handles", "打开的读文件句柄数", "gauge", |s| s.stats.file_pool.open_handles as u64),
+        ("learn_db_compaction_runs_total", "整理任务执行的轮数", "counter", |s| s.compaction_runs),
+        ("learn_db_compaction_files_reclaimed_total", "回收删除的数据文件数", "counter", |s| s.compaction_files),
+        ("learn_db_compaction_bytes_reclaimed_total", "回收删除的数据文件大小", "counter", |s| s.compaction_bytes),
+    ];
+    for (name, help, kind, value) in values {
+        w.header(name, help, kind);
+        for s in &snapshots {
+            w.sample(name, &[("db", s.name)], value(s));
+        }
+    }
+
+    let histograms: [HistogramMetric; 3] = [
+        ("learn_db_write_batch_size", "每批写入的条数", 1.0, |s| &s.write_batch_size),
+        ("learn_db_write_commit_duration_seconds", "每批写入（包括落盘）的耗时", 1e6, |s| &s.write_commit_latency_us),
+        ("learn_db_fsync_duration_seconds", "每次落盘的耗时", 1e6, |s| &s.fsync_latency_us),
+    ];
+    for (name, help, unit, view) in histograms {
+        w.header(name, help, "histogram");
+        for s in &snapshots {
+            w.histogram(name, &[("db", s.name)], view(&s.stats), unit);
+        }
+    }
+
+    let file_values: [ValueMetric<FileStats>; 3] = [
+        ("learn_db_file_bytes", "数据文件的大小", "gauge", |f| f.total_bytes),
+        ("learn_db_file_live_bytes", "数据文件中仍然有效的数据量", "gauge", |f| f.live_bytes),
+        ("learn_db_file_dead_bytes", "数据文件中已经被覆盖的数据量，回收后可以释放", "gauge", |f| f.dead_bytes()),
+    ];
+    for (name, help, kind, value) in file_values {
+        w.header(name, help, kind);
+        for s in &snapshots {
+            for f in &s.files {
+                w.sample(name, &[("db", s.name), ("file_id", &f.file_id.to_string())], value(f));
+            }
+        }
+    }
+
+    let routes = http.routes.lock().unwrap();
+    w.header("learn_db_http_requests_total", "HTTP请求数", "counter");
+    for (route, metrics) in routes.iter() {
+        for ((method, status), count) in &metrics.requests {
+            w.sample("learn_db_http_requests_total",
+                     &[("route", route), ("method", method), ("status", &status.to_string())], count);
+        }
+    }
+    w.header("learn_db_http_request_duration_seconds", "HTTP请求的处理耗时", "histogram");
+    for (route, metrics) in routes.iter() {
+        w.histogram("learn_db_http_request_duration_seconds", &[("route", route)], &metrics.latency.view(), 1e6);
+    }
+    w.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::{Histogram, PromWriter};
+
+    #[test]
+    pub fn test_histogram() {
+        let histogram = Histogram::new(&[1, 10, 100]);
+        for value in [0, 1, 5, 10, 50, 1000] {
+            histogram.observe(value);
+        }
+        let view = histogram.view();
+        assert_eq!(view.buckets, vec![(1, 2), (10, 4), (100, 5)]);
+        assert_eq!(view.count, 6);
+        assert_eq!(view.sum, 1066);
+    }
+
+    #[test]
+    pub fn test_prom_writer() {
+        let histogram = Histogram::new(&[100, 1000]);
+        histogram.observe(50);
+        histogram.observe(5000);
+
+        let mut w = PromWriter::default();
+        w.header("latency_seconds", "耗时", "histogram");
+        w.histogram("latency_seconds", &[("route", "/get/{key}")], &histogram.view(), 1e6);
+        w.sample("keys", &[("db", "a\"b")], 3);
+        assert_eq!(w.finish(), "# HELP latency_seconds 耗时\n\
+            # TYPE latency_seconds histogram\n\
+            latency_seconds_bucket{route=\"/get/{key}\",le=\"0.0001\"} 1\n\
+            latency_seconds_bucket{route=\"/get/{key}\",le=\"0.001\"} 1\n\
+            latency_seconds_bucket{route=\"/get/{key}\",le=\"+Inf\"} 2\n\
+            latency_seconds_sum{route=\"/get/{key}\"} 0.00505\n\
+            latency_seconds_count{route=\"/get/{key}\"} 2\n\
+            keys{db=\"a\\\"b\"} 3\n");
+    }
+}
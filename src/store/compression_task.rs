@@ -1,91 +1,366 @@
-use std::collections::HashMap;
-use std::fs::{DirEntry, read_dir};
+use std::fs::read_dir;
 use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
+use chrono::{Local, Timelike};
+use serde::Serialize;
 use tokio::fs::{File, OpenOptions};
-use tokio::io::AsyncWriteExt;
-use tokio::sync::oneshot;
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::sync::{oneshot, watch};
+use tokio::sync::mpsc::Receiver;
+use tokio::task::JoinHandle;
 use tokio::time;
 
-use crate::Config;
-use crate::custom_err::{common_err, CustomError, CustomResult};
+use crate::config::Config;
+use crate::custom_err::{common_err, corruption_err, CustomError, CustomResult};
 use crate::index::DataPosition;
-use crate::store::{get_file_id_from_path, get_index_file_name, get_log_file_name, get_tmp_index_file_name, is_log_file, read_by_dp, read_data_item};
+use crate::store::{get_file_id_from_path, get_index_file_name, get_log_file_name, is_log_file, read_data_item};
 use crate::store::data_manager::DataManager;
+use crate::store::jobs::{JobKind, now_ms};
+use crate::store::rate_limiter::RateLimiter;
 use crate::store::write_consumer::WriteEvent;
 
+/// 整理任务的统计信息
+#[derive(Default)]
+pub struct CompactionMetrics {
+    // 执行的轮数
+    pub runs: AtomicU64,
+    // 回收删除的数据文件数
+    pub files_reclaimed: AtomicU64,
+    // 回收删除的数据文件大小
+    pub bytes_reclaimed: AtomicU64,
+    // 最近一轮开始的时间，毫秒时间戳，0表示还没有执行过
+    last_run_at: AtomicU64,
+    // 暂停定时整理
+    paused: AtomicBool,
+    // 当前正在做的事情
+    state: Mutex<CompactionState>,
+}
+
+/// 整理任务当前的状态
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum CompactionState {
+    // 等待下一轮
+    #[default]
+    Idle,
+    // 正在生成索引文件
+    Indexing { file_id: u32 },
+    // 正在回收数据文件
+    Reclaiming { file_id: u32 },
+    // 任务已经结束，只读模式下不会启动
+    Stopped,
+}
+
+/// 整理任务的统计信息快照
+#[derive(Serialize, Debug)]
+pub struct CompactionView {
+    #[serde(flatten)]
+    pub state: CompactionState,
+    pub runs: u64,
+    pub files_reclaimed: u64,
+    pub bytes_reclaimed: u64,
+    pub last_run_at: u64,
+    pub paused: bool,
+}
+
+impl CompactionMetrics {
+    pub fn set_state(&self, state: CompactionState) {
+        *self.state.lock().unwrap() = state;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+    }
+
+    pub fn view(&self) -> CompactionView {
+        CompactionView {
+            state: self.state.lock().unwrap().clone(),
+            runs: self.runs.load(Ordering::Relaxed),
+            files_reclaimed: self.files_reclaimed.load(Ordering::Relaxed),
+            bytes_reclaimed: self.bytes_reclaimed.load(Ordering::Relaxed),
+            last_run_at: self.last_run_at.load(Ordering::Relaxed),
+            paused: self.is_paused(),
+        }
+    }
+}
+
+/// 手动触发的整理任务，和定时整理在同一个后台任务中串行执行
+pub struct CompactionJob {
+    pub id: u64,
+    pub kind: JobKind,
+}
+
+/// 一轮整理的结果
+#[derive(Default)]
+struct RoundResult {
+    indexed: usize,
+    index_failed: usize,
+    files_reclaimed: usize,
+    bytes_reclaimed: u64,
+    // 收到关闭信号，没有执行完
+    interrupted: bool,
+}
+
 /// 异步整理线，主要做两件事
 /// 1. 生成数据文件对应的索引文件
 /// 2. 当数据文件超过配置的个数时，回收掉最老的一个
-pub fn start_compression_task(cnf: Config, dm: DataManager) {
+///
+/// 每隔 compaction_interval_secs 执行一轮，暂停后或者不在 compaction_windows 的时间段内时不执行；
+/// 手动触发的任务不受这两项的限制。读写数据文件按 compaction_rate_limit 限速
+/// 收到关闭信号后，正在回收的文件会放弃回收并保留下来，然后任务结束
+pub fn start_compression_task(cnf: Config, dm: DataManager, mut job_recv: Receiver<CompactionJob>) -> JoinHandle<()> {
     tokio::spawn(async move {
-        loop {
-            let mut file_id_vec = scan_file_id_vec(&cnf.workspace);
-
-            if !file_id_vec.is_empty() {
-                // 最新的文件不需要处理，所以删除
-                file_id_vec.remove(file_id_vec.len() - 1);
-
-                for file_id in file_id_vec.clone() {
-                    let index_file_str = get_index_file_name(file_id, &cnf.workspace);
-                    let index_path = Path::new(&index_file_str);
-                    // 只处理不存在的场景
-                    if !index_path.exists() {
-                        if let Err(e) = generate_index_file(
-                            Path::new(&get_log_file_name(file_id, &cnf.workspace)),
-                            index_path,
-                        ).await {
-                            log::error!("生成索引文件失败,{:?}", e);
-                        }
+        let mut shutdown = dm.shutdown_signal();
+        let metrics = dm.compaction_metrics();
+        let jobs = dm.jobs();
+        let interval = time::Duration::from_secs(cnf.compaction_interval_secs);
+        // 启动后马上执行一轮
+        let mut next_round = time::Instant::now();
+        while !*shutdown.borrow() {
+            tokio::select! {
+                _ = time::sleep_until(next_round) => {
+                    if !metrics.is_paused() && in_compaction_window(&cnf) {
+                        run_round(&cnf, &dm, &mut shutdown, false).await;
                     }
+                    next_round = time::Instant::now() + interval;
                 }
-
-                // 回收超过数量的文件
-                while file_id_vec.len() >= cnf.max_file_num as usize {
-                    let file_id = file_id_vec.remove(0);
-                    let file_name = get_log_file_name(file_id, &cnf.workspace);
-                    log::info!("开始回收:{}", file_name);
-
-                    if let Ok(mut file) = File::open(Path::new(&file_name)).await {
-                        let mut pos = 0;
-                        let mut last_item = None;
-                        while let Ok((len, item)) = read_data_item(&mut file).await {
-                            if last_item.is_none() {
-                                last_item = Some(item.clone());
+                Some(job) = job_recv.recv() => {
+                    jobs.start(job.id);
+                    let res = match job.kind {
+                        JobKind::GenerateIndex => {
+                            let (indexed, failed) = generate_index_files(&cnf, &dm, &mut shutdown).await;
+                            metrics.set_state(CompactionState::Idle);
+                            if failed > 0 {
+                                Err(format!("{}个索引文件生成失败，详见日志", failed))
+                            } else {
+                                Ok(format!("生成了{}个索引文件", indexed))
                             }
-
-                            dm.push(WriteEvent::new_compare_event(item, DataPosition::new(
-                                file_id, pos,
-                            ))).await;
-                            pos = pos + len;
                         }
-
-                        // 因为写入是异步的，所以在最后写入一个同步消息，等这个消息有了回执，表明执行的都写完了
-                        if let Some(item) = last_item {
-                            let (tx, rx) = oneshot::channel();
-                            dm.push(WriteEvent::new_callback_event(item, Some(DataPosition::new(
-                                file_id, 0,
-                            )), tx)).await;
-                            rx.await;
+                        _ => {
+                            let res = run_round(&cnf, &dm, &mut shutdown, true).await;
+                            if res.interrupted {
+                                Err(String::from("收到关闭信号，整理没有完成"))
+                            } else {
+                                Ok(format!("生成了{}个索引文件，回收了{}个数据文件，释放{}字节",
+                                           res.indexed, res.files_reclaimed, res.bytes_reclaimed))
+                            }
                         }
-                    }
-                    std::fs::remove_file(Path::new(&file_name));
-                    std::fs::remove_file(Path::new(&get_index_file_name(file_id, &cnf.workspace)));
-                    log::info!("回收完成:{}", file_name);
+                    };
+                    jobs.finish(job.id, res);
                 }
+                _ = shutdown.changed() => {}
             }
+        }
+
+        // 还没有执行的任务不再执行
+        job_recv.close();
+        while let Ok(job) = job_recv.try_recv() {
+            jobs.finish(job.id, Err(String::from("数据库已关闭")));
+        }
+        metrics.set_state(CompactionState::Stopped);
+        log::info!("整理任务结束！");
+    })
+}
 
-            time::sleep(time::Duration::from_secs(10)).await
+/// 执行一轮整理
+/// force 为false时，只回收超过数量限制的文件，暂停后停止回收
+/// force 为true时，还会回收全部有无效数据的已封存文件
+async fn run_round(cnf: &Config, dm: &DataManager, shutdown: &mut watch::Receiver<bool>, force: bool) -> RoundResult {
+    let metrics = dm.compaction_metrics();
+    metrics.runs.fetch_add(1, Ordering::Relaxed);
+    metrics.last_run_at.store(now_ms(), Ordering::Relaxed);
+
+    let mut res = RoundResult::default();
+    (res.indexed, res.index_failed) = generate_index_files(cnf, dm, shutdown).await;
+
+    // 回收超过数量的文件，最新的文件正在写入，不计算在内
+    let sealed = sealed_file_ids(cnf);
+    let mut candidates: Vec<u32> = sealed.iter()
+        .take((sealed.len() + 1).saturating_sub(cnf.max_file_num as usize))
+        .copied()
+        .collect();
+    if force {
+        candidates.extend(dm.file_stats().iter()
+            .filter(|f| f.dead_bytes() > 0 && sealed.contains(&f.file_id))
+            .map(|f| f.file_id));
+        candidates.sort();
+        candidates.dedup();
+    }
+
+    for file_id in candidates {
+        if *shutdown.borrow() {
+            res.interrupted = true;
+            break;
+        }
+        if !force && metrics.is_paused() {
+            log::info!("整理任务已暂停，停止回收");
+            break;
         }
-    });
+        if !force && !in_compaction_window(cnf) {
+            log::info!("超出了允许整理的时间段，停止回收");
+            break;
+        }
+        metrics.set_state(CompactionState::Reclaiming { file_id });
+        if let Some(bytes) = reclaim_file(cnf, dm, file_id, &mut *shutdown).await {
+            res.files_reclaimed += 1;
+            res.bytes_reclaimed += bytes;
+        }
+    }
+    res.interrupted |= *shutdown.borrow();
+    metrics.set_state(CompactionState::Idle);
+    res
+}
+
+/// 当前是否在允许定时整理的时间段内，没有配置时间段时总是允许
+fn in_compaction_window(cnf: &Config) -> bool {
+    if cnf.compaction_windows.is_empty() {
+        return true;
+    }
+    let now = Local::now();
+    let minute = now.hour() * 60 + now.minute();
+    cnf.compaction_windows.iter().any(|w| w.contains(minute))
+}
+
+/// 已经封存的数据文件，最新的文件正在写入，不需要处理
+fn sealed_file_ids(cnf: &Config) -> Vec<u32> {
+    let mut file_id_vec = scan_file_id_vec(&cnf.workspace);
+    file_id_vec.pop();
+    file_id_vec
+}
+
+/// 为已经封存的数据文件生成缺少的索引文件，返回生成成功和失败的个数
+async fn generate_index_files(cnf: &Config, dm: &DataManager, shutdown: &mut watch::Receiver<bool>) -> (usize, usize) {
+    let metrics = dm.compaction_metrics();
+    let limiter = dm.compaction_limiter();
+    let (mut indexed, mut failed) = (0, 0);
+    for file_id in sealed_file_ids(cnf) {
+        if *shutdown.borrow() {
+            break;
+        }
+        let index_file_str = get_index_file_name(file_id, &cnf.workspace);
+        let index_path = Path::new(&index_file_str);
+        // 只处理不存在的场景
+        if !index_path.exists() {
+            metrics.set_state(CompactionState::Indexing { file_id });
+            let log_file_name = get_log_file_name(file_id, &cnf.workspace);
+            match generate_index_file_limited(Path::new(&log_file_name), index_path, Some((&limiter, &mut *shutdown))).await {
+                Ok(_) => indexed += 1,
+                Err(_) if *shutdown.borrow() => log::info!("收到关闭信号，停止生成索引文件"),
+                Err(e) => {
+                    log::error!("生成索引文件失败,{:?}", e);
+                    failed += 1;
+                }
+            }
+        }
+    }
+    (indexed, failed)
+}
+
+/// 回收单个数据文件：仍然有效的数据重新写入，然后删除文件
+/// 删除成功时返回文件的大小，放弃回收时返回None
+async fn reclaim_file(cnf: &Config, dm: &DataManager, file_id: u32, shutdown: &mut watch::Receiver<bool>) -> Option<u64> {
+    let metrics = dm.compaction_metrics();
+    let limiter = dm.compaction_limiter();
+    let file_name = get_log_file_name(file_id, &cnf.workspace);
+    log::info!("开始回收:{}", file_name);
+
+    // 打不开的文件（句柄数超限、没有权限等）里面可能还有有效数据，不能删除
+    let mut file = match File::open(Path::new(&file_name)).await {
+        Ok(file) => file,
+        Err(e) => {
+            log::error!("打开数据文件{}失败，放弃回收,{:?}", file_name, e);
+            return None;
+        }
+    };
+    let file_len = match file.metadata().await {
+        Ok(metadata) => metadata.len(),
+        Err(e) => {
+            log::error!("读取数据文件{}的大小失败，放弃回收,{:?}", file_name, e);
+            return None;
+        }
+    };
+    let mut pos = 0;
+    let mut last_item = None;
+    // 只有正好读到文件末尾才算读完，中间有无法解析的记录时，后面的有效数据还在文件里，不能删除
+    while pos as u64 != file_len {
+        let (len, item) = match read_data_item(&mut file).await {
+            Ok(next) => next,
+            Err(e) => {
+                log::error!("{}，保留文件，不回收", damaged_msg(Path::new(&file_name), pos, &e));
+                return None;
+            }
+        };
+        if *shutdown.borrow() {
+            break;
+        }
+        if last_item.is_none() {
+            last_item = Some(item.clone());
+        }
+
+        // 读和重新写入都要限速，已经被覆盖的数据不需要重新写入
+        if !acquire_or_shutdown(&limiter, len as u64, shutdown).await {
+            break;
+        }
+        let dp = DataPosition::new(file_id, pos);
+        pos += len;
+        if dm.index().find(&item.key).await.as_ref() != Some(&dp) {
+            continue;
+        }
+        if !acquire_or_shutdown(&limiter, len as u64, shutdown).await {
+            break;
+        }
+        if let Err(e) = dm.push_wait(WriteEvent::new_compare_event(item, dp)).await {
+            log::error!("回收数据写入失败,{:?}", e);
+        }
+    }
+
+    if *shutdown.borrow() {
+        log::info!("收到关闭信号，放弃回收:{}", file_name);
+        return None;
+    }
+
+    // 因为写入是异步的，所以在最后写入一个同步消息，等这个消息有了回执，表明执行的都写完了
+    // 旧文件马上要删除，所以这里必须等数据落盘
+    if let Some(item) = last_item {
+        let (tx, rx) = oneshot::channel();
+        let event = WriteEvent::new_callback_event(item, Some(DataPosition::new(
+            file_id, 0,
+        )), tx).with_durable(Some(true));
+        if dm.push_wait(event).await.is_err() || rx.await.is_err() {
+            log::error!("回收数据没有写入完成，保留文件:{}", file_name);
+            return None;
+        }
+    }
+    drop(file);
+    dm.invalidate_file(file_id);
+    let res = match std::fs::remove_file(Path::new(&file_name)) {
+        Ok(_) => {
+            metrics.files_reclaimed.fetch_add(1, Ordering::Relaxed);
+            metrics.bytes_reclaimed.fetch_add(file_len, Ordering::Relaxed);
+            Some(file_len)
+        }
+        Err(e) => {
+            log::error!("删除数据文件失败,{:?}", e);
+            None
+        }
+    };
+    // 索引文件可能还没有生成，忽略删除失败
+    let _ = std::fs::remove_file(Path::new(&get_index_file_name(file_id, &cnf.workspace)));
+    log::info!("回收完成:{}", file_name);
+    res
 }
 
 /// 从工作目录中扫描出数据文件，并解析出文件ID
 pub fn scan_file_id_vec(workspace: &String) -> Vec<u32> {
     let mut file_id_vec: Vec<u32> = read_dir(Path::new(workspace))
         .unwrap()
-        .filter(|f| f.is_ok())
-        .map(|f| f.unwrap())
+        .flatten()
         .filter(|f| f.path().is_file() && is_log_file(&f.path()))
         .map(|f| get_file_id_from_path(&f.path()))
         .collect();
@@ -93,12 +368,74 @@ pub fn scan_file_id_vec(workspace: &String) -> Vec<u32> {
     file_id_vec
 }
 
+/// 按限速等待，等待期间收到关闭信号时马上返回false
+async fn acquire_or_shutdown(limiter: &RateLimiter, bytes: u64, shutdown: &mut watch::Receiver<bool>) -> bool {
+    if *shutdown.borrow() {
+        return false;
+    }
+    tokio::select! {
+        _ = limiter.acquire(bytes) => !*shutdown.borrow(),
+        _ = shutdown.changed() => false,
+    }
+}
+
+/// 扫描数据文件，读取时按 limiter 限速；文件中间有无法解析的记录时返回 corruption 错误
+async fn read_log_index_limited(log_path: &Path, limiter: Option<(&RateLimiter, &mut watch::Receiver<bool>)>)
+                                -> CustomResult<Vec<(String, u32)>> {
+    let (entries, damage) = scan_log_index(log_path, limiter).await?;
+    match damage {
+        None => Ok(entries),
+        Some(e) => Err(e),
+    }
+}
+
+/// 扫描数据文件，返回能解析的key和偏移量
+/// 正好读到文件末尾时第二个值为空，否则是损坏位置的错误，前面的数据仍然返回
+/// 指定了 limiter 时按限速读取，收到关闭信号时返回错误
+pub async fn scan_log_index(log_path: &Path, mut limiter: Option<(&RateLimiter, &mut watch::Receiver<bool>)>)
+                            -> CustomResult<(Vec<(String, u32)>, Option<CustomError>)> {
+    let log_file = File::open(log_path).await?;
+    let file_len = log_file.metadata().await?.len();
+    let mut log_file = BufReader::new(log_file);
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while pos as u64 != file_len {
+        let (len, item) = match read_data_item(&mut log_file).await {
+            Ok(next) => next,
+            Err(e) => return Ok((entries, Some(corruption_err(damaged_msg(log_path, pos, &e))))),
+        };
+        if let Some((limiter, shutdown)) = limiter.as_mut() {
+            if !acquire_or_shutdown(limiter, len as u64, shutdown).await {
+                return Err(common_err(String::from("收到关闭信号，停止扫描数据文件")));
+            }
+        }
+        entries.push((item.key, pos));
+        pos += len;
+    }
+    Ok((entries, None))
+}
+
+/// 数据文件损坏时的提示，引导使用检查和修复工具
+fn damaged_msg(log_path: &Path, pos: u32, e: &CustomError) -> String {
+    format!("数据文件{:?}在位置{}无法解析,{}，请使用 learn-db verify 检查，learn-db repair 修复", log_path, pos, e.message)
+}
+
 /// 生成索引文件
 pub async fn generate_index_file(log_path: &Path, index_path: &Path) -> CustomResult<()> {
-    let mut log_file = File::open(log_path).await?;
+    generate_index_file_limited(log_path, index_path, None).await
+}
 
+/// 生成索引文件，读取数据文件时按 limiter 限速
+async fn generate_index_file_limited(log_path: &Path, index_path: &Path,
+                                     limiter: Option<(&RateLimiter, &mut watch::Receiver<bool>)>) -> CustomResult<()> {
+    let entries = read_log_index_limited(log_path, limiter).await?;
+    write_index_file(index_path, &entries).await
+}
+
+/// 把key和偏移量写入索引文件
+pub async fn write_index_file(index_path: &Path, entries: &[(String, u32)]) -> CustomResult<()> {
     let tmp_index_path = format!("{}.tmp", index_path.to_str()
-        .ok_or(common_err(format!("生成临时索引文件失败！")))?);
+        .ok_or(common_err(String::from("生成临时索引文件失败！")))?);
     log::info!("tmp_index_path = {}", tmp_index_path);
 
     // 先生成临时文件，防止写到一半出问题
@@ -110,20 +447,19 @@ pub async fn generate_index_file(log_path: &Path, index_path: &Path) -> CustomRe
         .open(Path::new(&tmp_index_path))
         .await?;
 
-    let mut pos = 0;
-    while let Ok((len, item)) = read_data_item(&mut log_file).await {
-        let key = item.key.as_bytes();
-        index_file.write_u32(key.len() as u32).await;
-        index_file.write(key).await;
-        index_file.write_u32(pos).await;
-
-        pos = pos + len;
+    let mut buf = Vec::new();
+    for (key, pos) in entries {
+        let key = key.as_bytes();
+        buf.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(&pos.to_be_bytes());
     }
-    index_file.sync_data().await;
+    index_file.write_all(&buf).await?;
+    index_file.sync_data().await?;
 
-    std::fs::rename(tmp_index_path, index_path);
+    std::fs::rename(tmp_index_path, index_path)?;
 
     log::info!("索引文件:{:?}生成完成", index_path);
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,100 +1,577 @@
-use std::borrow::Borrow;
 use std::path::Path;
 use std::result::Result::Ok;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
-use tokio::sync::mpsc;
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tokio::time::Instant;
 
-use crate::Config;
-use crate::custom_err::CustomResult;
+use crate::config::Config;
+use crate::store::manifest::Manifest;
+use crate::custom_err::{common_err, CustomError, CustomResult, invalid_argument_err, not_found_err, overloaded_err,
+                        payload_too_large_err, read_only_err};
 use crate::http_param::DataItem;
-use crate::index::DataPosition;
 use crate::index::dynamic_index::DynamicParallelIndexWrapper;
-use crate::store::{get_index_file_name, get_log_file_name, read_by_dp};
-use crate::store::compression_task::{generate_index_file, scan_file_id_vec, start_compression_task};
-use crate::store::write_consumer::{start_write_consumer, WriteEvent};
+use crate::index::DataPosition;
+use crate::store::{get_index_file_name, get_log_file_name, read_by_dp, read_record_by_dp};
+use crate::store::compression_task::{CompactionJob, CompactionMetrics, CompactionState, CompactionView,
+                                     generate_index_file, scan_file_id_vec, start_compression_task};
+use crate::store::jobs::{JobKind, Jobs, JobView};
+use crate::store::rate_limiter::RateLimiter;
+use crate::store::file_pool::{FilePool, FilePoolStats};
+use crate::store::recover_task::{recover_index_from_disk, RecoverProgress};
+use crate::store::value_cache::{CacheStats, ValueCache};
+use crate::store::workspace_lock::WorkspaceLock;
+use crate::metrics::HistogramView;
+use crate::store::write_consumer::{ActiveFile, start_write_consumer, WriteEvent, WriteMetrics};
+
+// 排队等待执行的手动任务的上限
+const MAX_PENDING_JOBS: usize = 16;
+// 检查磁盘是否可写时使用的探测文件的前缀，后面加上进程id和序号，并发检查时互不影响
+const DISK_PROBE_FILE_PREFIX: &str = "ready";
+// 探测文件的序号
+static DISK_PROBE_SEQ: AtomicU64 = AtomicU64::new(0);
 
 #[derive(Clone)]
 pub struct DataManager {
     // 工作目录
-    pub workspace: Arc<String>,
+    workspace: Arc<String>,
     // 写入生产者
     write_provider: Sender<WriteEvent>,
+    // 写入队列的长度
+    write_queue_size: usize,
+    // 写入队列满时最多等待的时间，为空表示一直等待
+    push_timeout: Option<time::Duration>,
+    // 写入队列满时是否直接失败
+    fast_fail: bool,
+    // key的最大长度
+    max_key_size: usize,
+    // value的最大长度
+    max_value_size: usize,
+    // 因为队列满被拒绝的写入次数
+    rejected: Arc<AtomicU64>,
+    // 读取数据文件失败的次数，不包括key不存在
+    read_errors: Arc<AtomicU64>,
     // 读取索引
     index: DynamicParallelIndexWrapper,
+    // 读文件的句柄池
+    file_pool: Arc<FilePool>,
+    // 热点数据的读缓存，容量配置为0时不开启
+    cache: Option<Arc<ValueCache>>,
+    // 当前正在写入的文件
+    active_file: Arc<ActiveFile>,
+    // 已封存的文件是否使用mmap读取
+    mmap_sealed_file: bool,
+    // 写入的统计信息
+    write_metrics: Arc<WriteMetrics>,
+    // 整理任务的统计信息
+    compaction_metrics: Arc<CompactionMetrics>,
+    // 手动触发的整理任务
+    compaction_jobs: Sender<CompactionJob>,
+    // 切换数据文件的请求
+    rotate_provider: Sender<oneshot::Sender<CustomResult<u32>>>,
+    // 手动触发的任务
+    jobs: Arc<Jobs>,
+    // 整理任务读写数据文件的限速
+    compaction_limiter: Arc<RateLimiter>,
+    // 打开的时间
+    started_at: Instant,
+    // 关闭信号，后台任务收到后会尽快结束
+    shutdown: Arc<watch::Sender<bool>>,
+    // 后台任务的句柄，关闭时等待它们结束
+    tasks: Arc<Mutex<BackgroundTasks>>,
+    // 关闭时是否为正在写入的文件生成索引文件
+    checkpoint_on_shutdown: bool,
+    // 只读模式，拒绝所有写入
+    read_only: bool,
+    // 工作目录的锁，只读模式不需要；关闭后释放
+    lock: Arc<Mutex<Option<WorkspaceLock>>>,
+}
+
+/// 后台任务的句柄
+#[derive(Default)]
+struct BackgroundTasks {
+    compression: Option<JoinHandle<()>>,
+    write_consumer: Option<JoinHandle<()>>,
 }
 
 impl DataManager {
-    pub async fn new(cnf: Config) -> DataManager {
+    /// 打开工作目录，恢复索引，并启动后台任务
+    /// 非只读模式需要先获取工作目录的锁，防止多个进程同时写入
+    /// 工作目录不存在时，按配置创建；磁盘格式比当前程序新时拒绝打开
+    /// 恢复进度写到 recover_progress 中，打开期间可以查看
+    pub async fn open(cnf: Config, recover_progress: Arc<RecoverProgress>) -> CustomResult<DataManager> {
+        if !Path::new(&cnf.workspace).is_dir() {
+            if !cnf.create_if_missing || cnf.read_only {
+                return Err(not_found_err(format!("workspace[{}]不存在", cnf.workspace)));
+            }
+            std::fs::create_dir_all(&cnf.workspace)?;
+            log::info!("创建工作目录:{}", cnf.workspace);
+        }
+        let lock = if cnf.read_only {
+            None
+        } else {
+            Some(WorkspaceLock::acquire(&cnf.workspace)?)
+        };
+        let manifest = Manifest::load_or_init(&cnf)?;
+        log::info!("workspace[{}]磁盘格式版本:{}", cnf.workspace, manifest.format_version);
+
         let max_file_id = calc_max_file_id(&cnf.workspace);
         log::info!("最新file_id={}", max_file_id);
 
-        let index = recover_index_from_disk(&cnf.workspace).await;
+        let index = recover_index_from_disk(&cnf, &recover_progress).await?;
 
-        let (send, mut recv) = mpsc::channel(10000);
+        let write_queue_size = cnf.write_queue_size.max(1);
+        let (send, recv) = mpsc::channel(write_queue_size);
+        let (rotate_provider, rotate_recv) = mpsc::channel(MAX_PENDING_JOBS);
+        let (compaction_jobs, compaction_job_recv) = mpsc::channel(MAX_PENDING_JOBS);
 
         // 写入的异步线程
-        start_write_consumer(cnf.clone(), max_file_id, recv, index.clone());
+        let active_file = Arc::new(ActiveFile::default());
+        active_file.id.store(max_file_id, Ordering::SeqCst);
+        let write_metrics = Arc::new(WriteMetrics::new());
+        let (shutdown, shutdown_signal) = watch::channel(false);
+        // 只读模式不启动写入，队列的接收端直接关闭，写入时会返回错误
+        let write_consumer = if cnf.read_only {
+            None
+        } else {
+            Some(start_write_consumer(cnf.clone(), recv, rotate_recv, index.clone(),
+                                      active_file.clone(), write_metrics.clone(),
+                                      shutdown_signal))
+        };
 
         let dm = DataManager {
             workspace: Arc::new(cnf.workspace.clone()),
             write_provider: send,
+            write_queue_size,
+            push_timeout: if cnf.write_push_timeout_ms > 0 {
+                Some(time::Duration::from_millis(cnf.write_push_timeout_ms))
+            } else {
+                None
+            },
+            fast_fail: cnf.write_fast_fail,
+            max_key_size: cnf.max_key_size,
+            max_value_size: cnf.max_value_size,
+            rejected: Arc::new(AtomicU64::new(0)),
+            read_errors: Arc::new(AtomicU64::new(0)),
             index,
+            file_pool: Arc::new(FilePool::new(cnf.workspace.clone(), cnf.max_open_files)),
+            cache: if cnf.value_cache_size > 0 {
+                Some(Arc::new(ValueCache::new(cnf.value_cache_size)))
+            } else {
+                None
+            },
+            active_file,
+            mmap_sealed_file: cnf.mmap_sealed_file,
+            write_metrics,
+            compaction_metrics: Arc::new(CompactionMetrics::default()),
+            compaction_jobs,
+            rotate_provider,
+            jobs: Arc::new(Jobs::default()),
+            compaction_limiter: Arc::new(RateLimiter::new(cnf.compaction_rate_limit)),
+            started_at: Instant::now(),
+            shutdown: Arc::new(shutdown),
+            tasks: Arc::new(Mutex::new(BackgroundTasks {
+                compression: None,
+                write_consumer,
+            })),
+            checkpoint_on_shutdown: cnf.checkpoint_on_shutdown && !cnf.read_only,
+            read_only: cnf.read_only,
+            lock: Arc::new(Mutex::new(lock)),
         };
-        // 整理文件的定时任务
-        start_compression_task(cnf.clone(),dm.clone());
-        dm
+        // 整理文件的定时任务，只读模式不需要
+        if cnf.read_only {
+            dm.compaction_metrics.set_state(CompactionState::Stopped);
+        } else {
+            let compression = start_compression_task(cnf.clone(), dm.clone(), compaction_job_recv);
+            dm.tasks.lock().unwrap().compression = Some(compression);
+        }
+        Ok(dm)
+    }
+
+    /// 关闭信号，值变为true时表示开始关闭
+    pub fn shutdown_signal(&self) -> watch::Receiver<bool> {
+        self.shutdown.subscribe()
     }
 
+    /// 优雅关闭
+    /// 1. 通知整理任务结束，正在回收的文件会被保留
+    /// 2. 写入队列不再接收新数据，已经在队列中的数据全部写入并落盘
+    /// 3. 按配置为正在写入的文件生成索引文件，下次启动时不需要再扫描数据文件
+    /// 4. 释放工作目录的锁
+    pub async fn shutdown(&self) {
+        log::info!("开始关闭数据库:{}", self.workspace);
+        let _ = self.shutdown.send(true);
+
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        if let Some(handle) = tasks.compression {
+            let _ = handle.await;
+        }
+        if let Some(handle) = tasks.write_consumer {
+            let _ = handle.await;
+        }
+
+        if self.checkpoint_on_shutdown {
+            let file_id = self.active_file.id.load(Ordering::SeqCst);
+            let log_file_name = get_log_file_name(file_id, &self.workspace);
+            let index_file_name = get_index_file_name(file_id, &self.workspace);
+            if Path::new(&log_file_name).exists() && !Path::new(&index_file_name).exists() {
+                if let Err(e) = generate_index_file(Path::new(&log_file_name), Path::new(&index_file_name)).await {
+                    log::error!("关闭时生成索引文件失败,{:?}", e);
+                }
+            }
+        }
+        // 全部写完后才释放锁
+        self.lock.lock().unwrap().take();
+        log::info!("数据库已关闭:{}", self.workspace);
+    }
+
+    /// 写入数据，队列满时按照配置快速失败或者等待，超时后返回 overloaded 错误
+    /// 只读模式直接返回 read_only 错误；key为空返回 invalid_argument，key或者value超过上限返回 payload_too_large
     pub async fn push(&self, event: WriteEvent) -> CustomResult<()> {
+        self.check_writable()?;
+        self.check_item(event.data_item())?;
+        if self.fast_fail {
+            return match self.write_provider.try_send(event) {
+                Ok(_) => Ok(()),
+                Err(TrySendError::Full(_)) => Err(self.reject()),
+                Err(TrySendError::Closed(_)) => Err(common_err(String::from("写入队列已关闭"))),
+            };
+        }
+        match self.push_timeout {
+            None => self.push_wait(event).await,
+            Some(timeout) => match time::timeout(timeout, self.write_provider.send(event)).await {
+                Ok(res) => Ok(res?),
+                Err(_) => Err(self.reject()),
+            },
+        }
+    }
+
+    /// 内部任务使用，队列满时一直等待，不受超时和快速失败的限制
+    pub async fn push_wait(&self, event: WriteEvent) -> CustomResult<()> {
+        self.check_writable()?;
         self.write_provider.send(event).await?;
         Ok(())
     }
 
-    pub async fn find(&self, key: &String) -> Option<String> {
-        let dp = self.index.find(key).await?;
+    fn check_writable(&self) -> CustomResult<()> {
+        if self.read_only {
+            return Err(read_only_err(format!("数据库[{}]以只读模式打开，不允许写入", self.workspace)));
+        }
+        Ok(())
+    }
 
-        read_by_dp(self.workspace.borrow(), &dp).await.ok()
+    /// 检查写入的key和value；内部任务通过 push_wait 重写已有的数据，不检查，调小上限后旧数据仍然可以被整理
+    fn check_item(&self, item: &DataItem) -> CustomResult<()> {
+        if item.key.is_empty() {
+            return Err(invalid_argument_err(String::from("key 不能为空")));
+        }
+        if item.key.len() > self.max_key_size {
+            return Err(payload_too_large_err(format!("key的长度{}超过上限{}", item.key.len(), self.max_key_size)));
+        }
+        if item.value.len() > self.max_value_size {
+            return Err(payload_too_large_err(format!("value的长度{}超过上限{}", item.value.len(), self.max_value_size)));
+        }
+        Ok(())
+    }
+
+    fn reject(&self) -> CustomError {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        overloaded_err(format!("写入队列已满，当前长度{}", self.write_queue_depth()))
+    }
+
+    /// 写入队列中还没有处理的数据条数
+    pub fn write_queue_depth(&self) -> usize {
+        self.write_queue_size - self.write_provider.capacity()
+    }
+
+    /// 查找key对应的value，key不存在时返回None
+    /// 数据文件丢失或者数据损坏时返回错误，并计入读取失败的次数，不会当成key不存在
+    pub async fn find(&self, key: &String) -> CustomResult<Option<String>> {
+        let dp = match self.index.find(key).await {
+            None => return Ok(None),
+            Some(dp) => dp,
+        };
+
+        if let Some(cache) = &self.cache {
+            if let Some(value) = cache.get(&dp) {
+                return Ok(Some(value));
+            }
+        }
+
+        let use_mmap = self.mmap_sealed_file && dp.file_id < self.active_file.id.load(Ordering::SeqCst);
+        let value = read_by_dp(&self.file_pool, &dp, use_mmap).await
+            .map_err(|e| self.read_failed(key, &dp, e))?;
+        if let Some(cache) = &self.cache {
+            cache.put(dp, value.clone());
+        }
+        Ok(Some(value))
+    }
+
+    fn read_failed(&self, key: &String, dp: &DataPosition, e: CustomError) -> CustomError {
+        self.read_errors.fetch_add(1, Ordering::Relaxed);
+        log::error!("读取key[{}]失败,位置{:?},{:?}", key, dp, e);
+        e
     }
-}
 
-/// 从磁盘中恢复索引
-pub async fn recover_index_from_disk(workspace: &String) -> DynamicParallelIndexWrapper {
-    log::info!("开始从磁盘恢复索引...");
+    /// 数据文件被回收删除后调用，关闭缓存的文件句柄
+    pub fn invalidate_file(&self, file_id: u32) {
+        self.file_pool.invalidate(file_id);
+    }
+
+    /// 读缓存和文件句柄池的统计信息
+    pub fn store_stats(&self) -> StoreStats {
+        StoreStats {
+            cache: self.cache.as_ref().map(|cache| cache.stats()),
+            file_pool: self.file_pool.stats(),
+            write_queue_depth: self.write_queue_depth(),
+            write_queue_capacity: self.write_queue_size,
+            write_rejected: self.rejected.load(Ordering::Relaxed),
+            read_errors: self.read_errors.load(Ordering::Relaxed),
+            write_batch_size: self.write_metrics.batch_size.view(),
+            write_commit_latency_us: self.write_metrics.commit_latency.view(),
+            fsync_latency_us: self.write_metrics.fsync_latency.view(),
+        }
+    }
+
+    /// 每个数据文件的大小和其中仍然有效的数据量
+    pub fn file_stats(&self) -> Vec<FileStats> {
+        let live_bytes = self.index.live_bytes();
+        scan_file_id_vec(&self.workspace).into_iter().map(|file_id| FileStats {
+            file_id,
+            // 文件可能正好被回收删除了
+            total_bytes: std::fs::metadata(get_log_file_name(file_id, &self.workspace)).map(|m| m.len()).unwrap_or(0),
+            live_bytes: live_bytes.get(&file_id).copied().unwrap_or(0),
+            has_index: Path::new(&get_index_file_name(file_id, &self.workspace)).exists(),
+        }).collect()
+    }
 
-    let index = DynamicParallelIndexWrapper::new(8);
-    let file_id_vec = scan_file_id_vec(workspace);
-    for file_id in file_id_vec {
-        let index_file_name = get_index_file_name(file_id, workspace);
-        let index_path = Path::new(&index_file_name);
-        if !index_path.exists() {
-            generate_index_file(
-                Path::new(&get_log_file_name(file_id, workspace)),
-                index_path,
-            ).await.unwrap();
+    /// 索引、数据文件、写入位置和整理任务的状态
+    pub async fn admin_stats(&self) -> AdminStats {
+        let key_count = self.index.size().await;
+        let parallel = self.index.parallel().await;
+        AdminStats {
+            key_count,
+            parallel,
+            load_factor: key_count as f64 / parallel as f64,
+            files: self.file_stats(),
+            active_file_id: self.active_file.id.load(Ordering::SeqCst),
+            active_offset: self.active_file.offset.load(Ordering::SeqCst),
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            read_only: self.read_only,
+            compaction: self.compaction_metrics.view(),
+            compaction_rate_limit: self.compaction_limiter.rate(),
         }
+    }
+
+    /// 查看key在磁盘上的位置和记录的元信息，key不存在时返回None
+    pub async fn inspect_key(&self, key: &String) -> CustomResult<Option<KeyInfo>> {
+        let dp = match self.index.find(key).await {
+            None => return Ok(None),
+            Some(dp) => dp,
+        };
+        let active = dp.file_id >= self.active_file.id.load(Ordering::SeqCst);
+        let record = read_record_by_dp(&self.file_pool, &dp, self.mmap_sealed_file && !active).await
+            .map_err(|e| self.read_failed(key, &dp, e))?;
+        Ok(Some(KeyInfo {
+            position: dp,
+            active,
+            len: record.encoded_len(),
+            timestamp: record.timestamp,
+            flags: record.flags,
+            key_len: record.key.len(),
+            value_len: record.value.len(),
+        }))
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// 写入任务是否还在运行，关闭或者异常退出后返回false
+    pub fn write_consumer_running(&self) -> bool {
+        self.tasks.lock().unwrap().write_consumer.as_ref().is_some_and(|handle| !handle.is_finished())
+    }
+
+    /// 在工作目录中写入并删除一个探测文件，检查磁盘是否可写
+    pub async fn check_disk_writable(&self) -> CustomResult<()> {
+        let seq = DISK_PROBE_SEQ.fetch_add(1, Ordering::Relaxed);
+        let path = Path::new(self.workspace.as_str())
+            .join(format!("{}.{}.{}.probe", DISK_PROBE_FILE_PREFIX, std::process::id(), seq));
+        tokio::fs::write(&path, b"ok").await?;
+        tokio::fs::remove_file(&path).await?;
+        Ok(())
+    }
+
+    pub fn compaction_metrics(&self) -> Arc<CompactionMetrics> {
+        self.compaction_metrics.clone()
+    }
+
+    pub fn compaction_limiter(&self) -> Arc<RateLimiter> {
+        self.compaction_limiter.clone()
+    }
+
+    /// 运行时修改整理任务的限速（字节/秒），0表示不限速
+    pub fn set_compaction_rate_limit(&self, rate: u64) -> CustomResult<()> {
+        self.check_writable()?;
+        self.compaction_limiter.set_rate(rate);
+        log::info!("数据库[{}]整理任务的限速修改为{}字节/秒", self.workspace, rate);
+        Ok(())
+    }
+
+    pub fn jobs(&self) -> Arc<Jobs> {
+        self.jobs.clone()
+    }
+
+    /// 查询手动触发的任务
+    pub fn job(&self, id: u64) -> Option<JobView> {
+        self.jobs.get(id)
+    }
 
-        let mut index_file = File::open(index_path).await.unwrap();
-        while let Ok((key, offset)) = read_index_from_file(&mut index_file).await {
-            index.push(&key, DataPosition::new(file_id, offset)).await;
+    /// 马上执行一轮整理，回收全部有无效数据的已封存文件，返回任务id
+    pub fn trigger_compaction(&self) -> CustomResult<u64> {
+        self.submit_compaction_job(JobKind::Compact)
+    }
+
+    /// 马上为已封存的数据文件生成缺少的索引文件，返回任务id
+    pub fn trigger_index_generation(&self) -> CustomResult<u64> {
+        self.submit_compaction_job(JobKind::GenerateIndex)
+    }
+
+    fn submit_compaction_job(&self, kind: JobKind) -> CustomResult<u64> {
+        self.check_writable()?;
+        let id = self.jobs.create(kind);
+        if self.compaction_jobs.try_send(CompactionJob { id, kind }).is_err() {
+            let msg = format!("排队的整理任务超过{}个，或者整理任务已经结束", MAX_PENDING_JOBS);
+            self.jobs.finish(id, Err(msg.clone()));
+            return Err(overloaded_err(msg));
         }
+        Ok(id)
+    }
 
-        log::info!("索引文件{:?}恢复完成", index_path);
+    /// 在后台切换到新的数据文件，返回任务id
+    pub fn trigger_rotation(&self) -> CustomResult<u64> {
+        self.check_writable()?;
+        let id = self.jobs.create(JobKind::Rotate);
+        let dm = self.clone();
+        tokio::spawn(async move {
+            dm.jobs.start(id);
+            let res = dm.rotate().await;
+            dm.jobs.finish(id, res.map(|file_id| format!("正在写入的文件:{}", file_id)).map_err(|e| e.message));
+        });
+        Ok(id)
     }
 
-    index
+    /// 切换到新的数据文件，返回切换后正在写入的文件id；正在写入的文件是空的时候不切换
+    pub async fn rotate(&self) -> CustomResult<u32> {
+        self.check_writable()?;
+        let (tx, rx) = oneshot::channel();
+        self.rotate_provider.send(tx).await.map_err(|_| common_err(String::from("写入任务已经结束")))?;
+        rx.await.map_err(|_| common_err(String::from("写入任务已经结束")))?
+    }
+
+    /// 暂停后台的定时整理，正在回收的文件处理完后停止，返回任务id
+    pub fn pause_compaction(&self) -> CustomResult<u64> {
+        self.set_compaction_paused(JobKind::PauseCompaction, true)
+    }
+
+    /// 恢复后台的定时整理，返回任务id
+    pub fn resume_compaction(&self) -> CustomResult<u64> {
+        self.set_compaction_paused(JobKind::ResumeCompaction, false)
+    }
+
+    fn set_compaction_paused(&self, kind: JobKind, paused: bool) -> CustomResult<u64> {
+        self.check_writable()?;
+        let id = self.jobs.create(kind);
+        self.compaction_metrics.set_paused(paused);
+        self.jobs.finish(id, Ok(format!("paused={}", paused)));
+        Ok(id)
+    }
+
+    pub fn index(&self) -> &DynamicParallelIndexWrapper {
+        &self.index
+    }
+}
+
+/// 存储层的统计信息
+#[derive(Serialize, Debug)]
+pub struct StoreStats {
+    // 读缓存，没有开启时为空
+    pub cache: Option<CacheStats>,
+    // 文件句柄池
+    pub file_pool: FilePoolStats,
+    // 写入队列中还没有处理的数据条数
+    pub write_queue_depth: usize,
+    // 写入队列的长度
+    pub write_queue_capacity: usize,
+    // 因为队列满被拒绝的写入次数
+    pub write_rejected: u64,
+    // 读取数据文件失败的次数
+    pub read_errors: u64,
+    // 每批写入的条数
+    pub write_batch_size: HistogramView,
+    // 每批写入（包括落盘）的耗时，单位微秒
+    pub write_commit_latency_us: HistogramView,
+    // 每次落盘的耗时，单位微秒
+    pub fsync_latency_us: HistogramView,
 }
 
-pub async fn read_index_from_file(index_file: &mut File) -> CustomResult<(String, u32)> {
-    let key_len = index_file.read_u32().await?;
-    let mut buffer = vec![Default::default(); key_len as usize];
-    index_file.read(&mut buffer).await?;
-    let offset = index_file.read_u32().await?;
-    let key = String::from_utf8(buffer)?;
-    Ok((key, offset))
+/// 单个数据文件的统计信息
+#[derive(Serialize, Debug)]
+pub struct FileStats {
+    pub file_id: u32,
+    // 文件大小
+    pub total_bytes: u64,
+    // 仍然被索引引用的数据量
+    pub live_bytes: u64,
+    // 是否已经生成了索引文件
+    pub has_index: bool,
+}
+
+/// 管理接口返回的状态
+#[derive(Serialize, Debug)]
+pub struct AdminStats {
+    pub key_count: u64,
+    // 索引的并行度
+    pub parallel: u64,
+    // 平均每个链表上的key数量，超过阈值时扩容
+    pub load_factor: f64,
+    pub files: Vec<FileStats>,
+    // 正在写入的文件和写入位置
+    pub active_file_id: u32,
+    pub active_offset: u32,
+    pub uptime_secs: u64,
+    pub read_only: bool,
+    pub compaction: CompactionView,
+    // 整理任务的限速（字节/秒），0表示不限速
+    pub compaction_rate_limit: u64,
+}
+
+/// key在磁盘上的位置和记录的元信息
+#[derive(Serialize, Debug)]
+pub struct KeyInfo {
+    pub position: DataPosition,
+    // 是否在正在写入的文件中
+    pub active: bool,
+    // 整条记录占用的字节数
+    pub len: usize,
+    pub timestamp: u64,
+    pub flags: u8,
+    pub key_len: usize,
+    pub value_len: usize,
+}
+
+impl FileStats {
+    /// 已经被覆盖的数据量
+    pub fn dead_bytes(&self) -> u64 {
+        self.total_bytes.saturating_sub(self.live_bytes)
+    }
 }
 
 pub fn calc_max_file_id(workspace: &String) -> u32 {
@@ -102,38 +579,329 @@ pub fn calc_max_file_id(workspace: &String) -> u32 {
     if vec.is_empty() {
         1
     } else {
-        vec.get(vec.len() - 1).unwrap() + 1
+        vec.last().unwrap() + 1
+    }
+}
+
+#[cfg(test)]
+impl DataManager {
+    /// 测试中使用，不需要查看恢复进度
+    pub async fn new(cnf: Config) -> CustomResult<DataManager> {
+        DataManager::open(cnf, Arc::new(RecoverProgress::default())).await
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{Config, init_log};
+    use tokio::sync::oneshot;
+
+    use crate::config::Config;
+    use crate::init_log;
+    use crate::custom_err::ErrorKind;
     use crate::http_param::DataItem;
     use crate::store::data_manager::DataManager;
+    use crate::store::{get_index_file_name, get_log_file_name, get_manifest_file_name};
+    use crate::store::jobs::JobStatus;
     use crate::store::write_consumer::WriteEvent;
+    use crate::test_util::test_workspace;
 
     #[tokio::test]
     async fn test_new() {
-        init_log();
+        init_log("log4rs.yaml");
 
-        let config = Config::new(String::from("/Users/yang/logs/learn-db"));
+        let workspace = test_workspace("dm");
+        let config = Config::new(workspace.to_string());
 
 
-        let dm = DataManager::new(config).await;
+        let dm = DataManager::new(config).await.unwrap();
 
 
         for i in 0..10000 {
             dm.push(WriteEvent::new_simple_event(DataItem {
                 key: format!("name_{}", i),
                 value: format!("ygy_{}", i),
-            })).await;
+            })).await.unwrap();
         }
 
-        let res = dm.find(&format!("name_{}", 0)).await;
+        let res = dm.find(&format!("name_{}", 0)).await.unwrap();
         log::info!("read res={:?}", res);
 
 
         //std::thread::sleep(std::time::Duration::from_secs(10));
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_isolated_instances() {
+        let mut dms = Vec::new();
+        for name in ["a", "b"] {
+            let workspace = test_workspace(&format!("dm-{}", name));
+            let dm = DataManager::new(Config::new(workspace.to_string())).await.unwrap();
+
+            // 两个库的 file_id 相同，写入同一个key
+            let (tx, rx) = oneshot::channel();
+            dm.push(WriteEvent::new_callback_event(DataItem {
+                key: String::from("name"),
+                value: format!("value_{}", name),
+            }, None, tx)).await.unwrap();
+            rx.await.unwrap();
+            dms.push((dm, workspace));
+        }
+
+        assert_eq!(dms[0].0.find(&String::from("name")).await.unwrap(), Some(String::from("value_a")));
+        assert_eq!(dms[1].0.find(&String::from("name")).await.unwrap(), Some(String::from("value_b")));
+    }
+
+    #[tokio::test]
+    async fn test_fast_fail_when_queue_full() {
+        let workspace = test_workspace("dm-fast-fail");
+        let mut cnf = Config::new(workspace.to_string());
+        cnf.write_queue_size = 1;
+        cnf.write_fast_fail = true;
+        let dm = DataManager::new(cnf).await.unwrap();
+
+        let item = DataItem { key: String::from("k"), value: String::from("v") };
+        // 单线程运行时，写入消费者没有机会消费，第二条会因为队列满而失败
+        dm.push(WriteEvent::new_simple_event(item.clone())).await.unwrap();
+        let err = dm.push(WriteEvent::new_simple_event(item)).await.unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Overloaded);
+        let stats = dm.store_stats();
+        assert_eq!(stats.write_queue_depth, 1);
+        assert_eq!(stats.write_rejected, 1);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_queue() {
+        let workspace = test_workspace("dm-shutdown");
+        let dm = DataManager::new(Config::new(workspace.clone())).await.unwrap();
+
+        // 异步写入，不等待回执
+        for i in 0..1000 {
+            dm.push(WriteEvent::new_simple_event(DataItem {
+                key: format!("name_{}", i),
+                value: format!("ygy_{}", i),
+            })).await.unwrap();
+        }
+        dm.shutdown().await;
+        assert!(dm.push(WriteEvent::new_simple_event(DataItem {
+            key: String::from("late"),
+            value: String::from("late"),
+        })).await.is_err());
+        // 正在写入的文件已经生成了索引文件
+        assert!(std::path::Path::new(&get_index_file_name(1, &workspace)).exists());
+
+        let dm = DataManager::new(Config::new(workspace.to_string())).await.unwrap();
+        for i in 0..1000 {
+            assert_eq!(dm.find(&format!("name_{}", i)).await.unwrap(), Some(format!("ygy_{}", i)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_while_throttled() {
+        let workspace = test_workspace("dm-throttled");
+        let mut cnf = Config::new(workspace.clone());
+        cnf.compaction_interval_secs = 3600;
+        // 每秒1字节，回收一条记录就要等几十秒
+        cnf.compaction_rate_limit = 1;
+        let dm = DataManager::new(cnf).await.unwrap();
+
+        for value in ["1", "2"] {
+            let (tx, rx) = oneshot::channel();
+            dm.push(WriteEvent::new_callback_event(DataItem { key: String::from("a"), value: String::from(value) }, None, tx))
+                .await.unwrap();
+            rx.await.unwrap();
+        }
+        let first = dm.rotate().await.unwrap() - 1;
+        dm.trigger_compaction().unwrap();
+        tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+
+        // 限速等待期间也能马上关闭，没有回收完的文件保留下来
+        tokio::time::timeout(tokio::time::Duration::from_secs(2), dm.shutdown()).await.unwrap();
+        assert!(std::path::Path::new(&get_log_file_name(first, &workspace)).exists());
+    }
+
+    #[tokio::test]
+    async fn test_workspace_locked() {
+        let workspace = test_workspace("dm-lock");
+
+        let _dm = DataManager::new(Config::new(workspace.clone())).await.unwrap();
+        let err = DataManager::new(Config::new(workspace.clone())).await.err().unwrap();
+        assert!(err.message.contains(&std::process::id().to_string()));
+
+        // 只读模式不需要锁
+        let mut cnf = Config::new(workspace.to_string());
+        cnf.read_only = true;
+        assert!(DataManager::new(cnf).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_read_only_instances() {
+        let workspace = test_workspace("dm-read-only");
+
+        let mut cnf = Config::new(workspace.clone());
+        cnf.checkpoint_on_shutdown = false;
+        let dm = DataManager::new(cnf).await.unwrap();
+        for i in 0..100 {
+            dm.push(WriteEvent::new_simple_event(DataItem {
+                key: format!("name_{}", i),
+                value: format!("ygy_{}", i),
+            })).await.unwrap();
+        }
+        dm.shutdown().await;
+
+        // 两个只读实例共享同一个工作目录，都不会生成索引文件
+        let mut cnf = Config::new(workspace.clone());
+        cnf.read_only = true;
+        let readers = [DataManager::new(cnf.clone()).await.unwrap(), DataManager::new(cnf).await.unwrap()];
+        assert!(!std::path::Path::new(&get_index_file_name(1, &workspace)).exists());
+        for reader in &readers {
+            assert_eq!(reader.find(&String::from("name_99")).await.unwrap(), Some(String::from("ygy_99")));
+            let err = reader.push(WriteEvent::new_simple_event(DataItem {
+                key: String::from("k"),
+                value: String::from("v"),
+            })).await.unwrap_err();
+            assert_eq!(err.kind, ErrorKind::ReadOnly);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_if_missing() {
+        let workspace = test_workspace("dm-create");
+        let dir = std::path::Path::new(workspace.as_str()).join("nested");
+        let mut cnf = Config::new(dir.to_str().unwrap().to_string());
+
+        cnf.create_if_missing = false;
+        assert!(DataManager::new(cnf.clone()).await.is_err());
+
+        cnf.create_if_missing = true;
+        let dm = DataManager::new(cnf.clone()).await.unwrap();
+        assert!(std::path::Path::new(&get_manifest_file_name(&cnf.workspace)).exists());
+        dm.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_admin_stats() {
+        let workspace = test_workspace("dm-admin");
+        let dm = DataManager::new(Config::new(workspace.to_string())).await.unwrap();
+
+        for value in ["1", "22"] {
+            let (tx, rx) = oneshot::channel();
+            let item = DataItem { key: String::from("a"), value: String::from(value) };
+            dm.push(WriteEvent::new_callback_event(item, None, tx)).await.unwrap();
+            rx.await.unwrap();
+        }
+
+        let stats = dm.admin_stats().await;
+        assert_eq!(stats.key_count, 1);
+        assert_eq!(stats.files.len(), 1);
+        let file = &stats.files[0];
+        assert_eq!(file.file_id, stats.active_file_id);
+        assert_eq!(file.total_bytes, stats.active_offset as u64);
+        // 第一条记录已经被覆盖
+        assert!(file.dead_bytes() > 0);
+        assert!(!file.has_index);
+
+        let info = dm.inspect_key(&String::from("a")).await.unwrap().unwrap();
+        assert_eq!(info.position.offset as u64, file.dead_bytes());
+        assert_eq!(info.len as u64, file.live_bytes);
+        assert_eq!(info.value_len, 2);
+        assert!(info.active);
+        assert_eq!(dm.inspect_key(&String::from("b")).await.unwrap().map(|i| i.len), None);
+        dm.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_manual_jobs() {
+        let workspace = test_workspace("dm-jobs");
+        let mut cnf = Config::new(workspace.to_string());
+        cnf.compaction_interval_secs = 3600;
+        let dm = DataManager::new(cnf.clone()).await.unwrap();
+
+        async fn wait_job(dm: &DataManager, id: u64) -> JobStatus {
+            loop {
+                let status = dm.job(id).unwrap().status;
+                if !matches!(status, JobStatus::Pending | JobStatus::Running) {
+                    return status;
+                }
+                tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+            }
+        }
+        async fn put(dm: &DataManager, key: &str, value: &str) {
+            let (tx, rx) = oneshot::channel();
+            let item = DataItem { key: String::from(key), value: String::from(value) };
+            dm.push(WriteEvent::new_callback_event(item, None, tx)).await.unwrap();
+            rx.await.unwrap();
+        }
+
+        // 第一个文件中的 a 被覆盖，b 仍然有效
+        put(&dm, "a", "1").await;
+        put(&dm, "b", "2").await;
+        let first = dm.admin_stats().await.active_file_id;
+        let id = dm.trigger_rotation().unwrap();
+        assert!(matches!(wait_job(&dm, id).await, JobStatus::Succeeded { .. }));
+        assert_eq!(dm.admin_stats().await.active_file_id, first + 1);
+        // 空文件不再切换
+        assert_eq!(dm.rotate().await.unwrap(), first + 1);
+        put(&dm, "a", "3").await;
+
+        let id = dm.pause_compaction().unwrap();
+        assert!(dm.job(id).is_some());
+        assert!(dm.admin_stats().await.compaction.paused);
+
+        // 暂停后手动触发的整理仍然执行
+        let id = dm.trigger_compaction().unwrap();
+        assert!(matches!(wait_job(&dm, id).await, JobStatus::Succeeded { .. }));
+        assert!(!std::path::Path::new(&get_log_file_name(first, &cnf.workspace)).exists());
+        assert_eq!(dm.find(&String::from("a")).await.unwrap(), Some(String::from("3")));
+        assert_eq!(dm.find(&String::from("b")).await.unwrap(), Some(String::from("2")));
+
+        dm.resume_compaction().unwrap();
+        assert!(!dm.admin_stats().await.compaction.paused);
+        dm.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_find_read_error() {
+        let workspace = test_workspace("dm-read-error");
+        let mut cnf = Config::new(workspace.to_string());
+        // 关闭读缓存，保证每次都从数据文件读取
+        cnf.value_cache_size = 0;
+        let dm = DataManager::new(cnf.clone()).await.unwrap();
+
+        let (tx, rx) = oneshot::channel();
+        let item = DataItem { key: String::from("a"), value: String::from("value_a") };
+        dm.push(WriteEvent::new_callback_event(item, None, tx)).await.unwrap();
+        rx.await.unwrap();
+        assert_eq!(dm.find(&String::from("a")).await.unwrap(), Some(String::from("value_a")));
+        assert_eq!(dm.find(&String::from("b")).await.unwrap(), None);
+
+        // 改掉value的最后一个字节，校验失败，不能当成key不存在
+        let file_id = dm.admin_stats().await.active_file_id;
+        let log_file_name = get_log_file_name(file_id, &cnf.workspace);
+        let mut data = std::fs::read(&log_file_name).unwrap();
+        *data.last_mut().unwrap() ^= 0xff;
+        std::fs::write(&log_file_name, data).unwrap();
+        let err = dm.find(&String::from("a")).await.err().unwrap();
+        assert_eq!(err.kind, ErrorKind::Corruption);
+        assert_eq!(dm.store_stats().read_errors, 1);
+        dm.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_push_limits() {
+        let workspace = test_workspace("dm-limits");
+        let mut cnf = Config::new(workspace.to_string());
+        cnf.max_key_size = 4;
+        cnf.max_value_size = 8;
+        let dm = DataManager::new(cnf).await.unwrap();
+
+        let push = |key: &str, value: &str| {
+            let item = DataItem { key: String::from(key), value: String::from(value) };
+            dm.push(WriteEvent::new_simple_event(item))
+        };
+        assert_eq!(push("", "v").await.err().unwrap().kind, ErrorKind::InvalidArgument);
+        assert_eq!(push("abcde", "v").await.err().unwrap().kind, ErrorKind::PayloadTooLarge);
+        assert_eq!(push("abcd", "123456789").await.err().unwrap().kind, ErrorKind::PayloadTooLarge);
+        assert!(push("abcd", "12345678").await.is_ok());
+        dm.shutdown().await;
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use memmap2::Mmap;
+use serde::Serialize;
+
+use crate::custom_err::CustomResult;
+use crate::store::get_log_file_name;
+
+/// 读取数据用的文件句柄
+#[derive(Clone)]
+pub enum ReadHandle {
+    // 普通文件，使用pread按位置读取，多个线程并发读也不会互相影响
+    File(Arc<std::fs::File>),
+    // 已经封存（不会再写入）的文件，直接映射到内存
+    Mmap(Arc<Mmap>),
+}
+
+/// 读文件的句柄池，每个 DataManager 一个
+/// 最多同时打开 capacity 个文件，超过后关闭最久没有使用的；
+/// 文件被回收删除时，需要调用 invalidate 及时关闭
+pub struct FilePool {
+    // 工作目录
+    dir: String,
+    // 最多打开的文件数
+    capacity: usize,
+    inner: Mutex<PoolInner>,
+    // 累计打开过的文件数
+    opened: AtomicU64,
+    // 因为超过容量被关闭的文件数
+    evicted: AtomicU64,
+}
+
+struct PoolInner {
+    // file_id -> (句柄, 最近一次使用的序号)
+    map: HashMap<u32, (ReadHandle, u64)>,
+    // 使用序号 -> file_id，序号最小的就是最久没有使用的
+    order: BTreeMap<u64, u32>,
+    // 单调递增的使用序号
+    tick: u64,
+    // 调用 invalidate 的次数，打开文件期间有变化时，打开的句柄不放入缓存
+    invalidations: u64,
+}
+
+/// 句柄池的统计信息
+#[derive(Serialize, Debug)]
+pub struct FilePoolStats {
+    pub capacity: usize,
+    pub open_handles: usize,
+    pub opened_total: u64,
+    pub evicted_total: u64,
+}
+
+impl FilePool {
+    pub fn new(dir: String, capacity: usize) -> FilePool {
+        FilePool {
+            dir,
+            capacity: capacity.max(1),
+            inner: Mutex::new(PoolInner {
+                map: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+                invalidations: 0,
+            }),
+            opened: AtomicU64::new(0),
+            evicted: AtomicU64::new(0),
+        }
+    }
+
+    /// 获取文件句柄，没有的话就打开文件
+    /// 文件刚封存时，缓存的可能还是普通句柄，这时会替换成mmap
+    pub fn get(&self, file_id: u32, use_mmap: bool) -> CustomResult<ReadHandle> {
+        let invalidations = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.tick += 1;
+            let tick = inner.tick;
+            let PoolInner { map, order, .. } = &mut *inner;
+            if let Some((handle, last)) = map.get_mut(&file_id) {
+                if !(use_mmap && matches!(handle, ReadHandle::File(_))) {
+                    order.remove(last);
+                    order.insert(tick, file_id);
+                    *last = tick;
+                    return Ok(handle.clone());
+                }
+            }
+            inner.invalidations
+        };
+
+        // 打开文件不持有锁，并发打开同一个文件时，后放入的会覆盖先放入的
+        let file = std::fs::File::open(get_log_file_name(file_id, &self.dir))?;
+        let handle = if use_mmap {
+            // 封存的文件不会再被修改，只会在回收时被整体删除，删除后已经映射的内存仍然有效
+            ReadHandle::Mmap(Arc::new(unsafe { Mmap::map(&file)? }))
+        } else {
+            ReadHandle::File(Arc::new(file))
+        };
+        self.opened.fetch_add(1, Ordering::Relaxed);
+
+        let mut inner = self.inner.lock().unwrap();
+        // 打开期间文件可能已经被回收删除，放入缓存的话就不会再被关闭了，只给这一次读取使用
+        if inner.invalidations != invalidations {
+            return Ok(handle);
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        if let Some((_, last)) = inner.map.insert(file_id, (handle.clone(), tick)) {
+            inner.order.remove(&last);
+        }
+        inner.order.insert(tick, file_id);
+
+        while inner.map.len() > self.capacity {
+            let (_, oldest) = match inner.order.pop_first() {
+                None => break,
+                Some(v) => v,
+            };
+            inner.map.remove(&oldest);
+            self.evicted.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(handle)
+    }
+
+    /// 文件被删除时调用，关闭缓存的句柄
+    /// 正在使用该句柄的读取不受影响，用完后才会真正关闭
+    pub fn invalidate(&self, file_id: u32) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.invalidations += 1;
+        if let Some((_, last)) = inner.map.remove(&file_id) {
+            inner.order.remove(&last);
+        }
+    }
+
+    pub fn stats(&self) -> FilePoolStats {
+        let inner = self.inner.lock().unwrap();
+        FilePoolStats {
+            capacity: self.capacity,
+            open_handles: inner.map.len(),
+            opened_total: self.opened.load(Ordering::Relaxed),
+            evicted_total: self.evicted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::file_pool::FilePool;
+    use crate::store::get_log_file_name;
+    use crate::test_util::test_workspace;
+
+    #[test]
+    pub fn test_evict_and_invalidate() {
+        let workspace = test_workspace("file-pool");
+        for file_id in 1..=3 {
+            std::fs::write(get_log_file_name(file_id, &workspace), b"data").unwrap();
+        }
+
+        let pool = FilePool::new(workspace.clone(), 2);
+        pool.get(1, false).unwrap();
+        pool.get(2, false).unwrap();
+        pool.get(1, false).unwrap();
+        // 2 是最久没有使用的，会被关闭
+        pool.get(3, false).unwrap();
+        let stats = pool.stats();
+        assert_eq!(stats.open_handles, 2);
+        assert_eq!(stats.opened_total, 3);
+        assert_eq!(stats.evicted_total, 1);
+
+        pool.invalidate(1);
+        std::fs::remove_file(get_log_file_name(1, &workspace)).unwrap();
+        assert_eq!(pool.stats().open_handles, 1);
+        assert!(pool.get(1, false).is_err());
+        assert!(pool.get(3, true).is_ok());
+    }
+}
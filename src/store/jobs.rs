@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+// 最多保留的已经结束的任务数，超过后删除最老的
+const MAX_FINISHED_JOBS: usize = 100;
+
+/// 手动触发的后台任务类型
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    // 马上执行一轮整理，回收全部有无效数据的已封存文件
+    Compact,
+    // 马上为已封存的数据文件生成缺少的索引文件
+    GenerateIndex,
+    // 马上切换到新的数据文件
+    Rotate,
+    // 暂停后台的定时整理
+    PauseCompaction,
+    // 恢复后台的定时整理
+    ResumeCompaction,
+}
+
+/// 任务的状态
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Succeeded { message: String },
+    Failed { error: String },
+}
+
+impl JobStatus {
+    fn is_finished(&self) -> bool {
+        matches!(self, JobStatus::Succeeded { .. } | JobStatus::Failed { .. })
+    }
+}
+
+/// 任务的快照
+#[derive(Serialize, Debug, Clone)]
+pub struct JobView {
+    pub id: u64,
+    pub kind: JobKind,
+    #[serde(flatten)]
+    pub status: JobStatus,
+    // 毫秒时间戳
+    pub created_at: u64,
+    // 结束的时间，还没结束时为0
+    pub finished_at: u64,
+}
+
+/// 手动触发的任务列表，任务id从1开始递增
+#[derive(Default)]
+pub struct Jobs {
+    next_id: AtomicU64,
+    jobs: Mutex<BTreeMap<u64, JobView>>,
+}
+
+impl Jobs {
+    /// 登记一个新任务，返回任务id
+    pub fn create(&self, kind: JobKind) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.insert(id, JobView { id, kind, status: JobStatus::Pending, created_at: now_ms(), finished_at: 0 });
+
+        let finished: Vec<u64> = jobs.values().filter(|job| job.status.is_finished()).map(|job| job.id).collect();
+        for id in finished.iter().take(finished.len().saturating_sub(MAX_FINISHED_JOBS)) {
+            jobs.remove(id);
+        }
+        id
+    }
+
+    pub fn start(&self, id: u64) {
+        self.update(id, JobStatus::Running);
+    }
+
+    /// 任务结束，成功时带上结果的说明，失败时带上错误信息
+    pub fn finish(&self, id: u64, res: Result<String, String>) {
+        self.update(id, match res {
+            Ok(message) => JobStatus::Succeeded { message },
+            Err(error) => JobStatus::Failed { error },
+        });
+    }
+
+    fn update(&self, id: u64, status: JobStatus) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(&id) {
+            if status.is_finished() {
+                job.finished_at = now_ms();
+            }
+            job.status = status;
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<JobView> {
+        self.jobs.lock().unwrap().get(&id).cloned()
+    }
+
+    /// 全部任务，最新的在前面
+    pub fn list(&self) -> Vec<JobView> {
+        self.jobs.lock().unwrap().values().rev().cloned().collect()
+    }
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
@@ -0,0 +1,155 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::custom_err::{corruption_err, CustomResult, needs_upgrade_err, unsupported_format_err};
+use crate::store::compression_task::scan_file_id_vec;
+use crate::store::get_manifest_file_name;
+
+// 当前程序使用的磁盘格式版本，格式变化时加1，旧格式需要先执行 upgrade 才能打开
+// 1: 数据文件中每条记录是 长度 + DataItem的JSON
+// 2: 记录头带crc32校验、时间戳和标记，key和value是二进制，见 record.rs
+pub const FORMAT_VERSION: u32 = 2;
+// 没有描述文件、但是已经有数据文件的目录，是最早的版本创建的
+pub const LEGACY_FORMAT_VERSION: u32 = 1;
+
+/// 工作目录的描述文件，记录磁盘格式的版本、创建时间和创建时的引擎参数
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Manifest {
+    // 磁盘格式的版本
+    pub format_version: u32,
+    // 正在升级到的版本，升级完成前不允许打开
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upgrading_to: Option<u32>,
+    // 创建时间，unix时间戳（秒）
+    pub created_at: u64,
+    // 创建时的引擎参数，只用于排查问题，启动时以配置为准
+    #[serde(default)]
+    pub options: ManifestOptions,
+}
+
+/// 创建工作目录时的引擎参数
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct ManifestOptions {
+    pub max_file_size: u32,
+    pub max_file_num: u32,
+    pub durability: String,
+}
+
+impl Manifest {
+    pub fn new(cnf: &Config, format_version: u32) -> Manifest {
+        Manifest {
+            format_version,
+            upgrading_to: None,
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            options: ManifestOptions {
+                max_file_size: cnf.max_file_size,
+                max_file_num: cnf.max_file_num,
+                durability: cnf.durability.to_string(),
+            },
+        }
+    }
+
+    /// 读取工作目录的描述文件，不存在时返回None
+    pub fn read(workspace: &String) -> CustomResult<Option<Manifest>> {
+        let file_name = get_manifest_file_name(workspace);
+        if !Path::new(&file_name).exists() {
+            return Ok(None);
+        }
+        let content = std::fs::read_to_string(&file_name)?;
+        let manifest = serde_json::from_str(&content)
+            .map_err(|e| corruption_err(format!("描述文件{}解析失败,{}", file_name, e)))?;
+        Ok(Some(manifest))
+    }
+
+    /// 先写临时文件再重命名，防止写到一半出问题
+    pub fn write(&self, workspace: &String) -> CustomResult<()> {
+        let file_name = get_manifest_file_name(workspace);
+        let tmp_file_name = format!("{}.tmp", file_name);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .create(true)
+            .open(&tmp_file_name)?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        file.sync_data()?;
+        std::fs::rename(&tmp_file_name, &file_name)?;
+        // 重命名也需要落盘
+        std::fs::File::open(workspace)?.sync_all()?;
+        Ok(())
+    }
+
+    /// 读取描述文件，没有描述文件时，空目录使用当前版本，有数据文件的目录是最早的版本
+    pub fn read_or_detect(cnf: &Config) -> CustomResult<(Manifest, bool)> {
+        Ok(match Manifest::read(&cnf.workspace)? {
+            Some(manifest) => (manifest, true),
+            None if scan_file_id_vec(&cnf.workspace).is_empty() => (Manifest::new(cnf, FORMAT_VERSION), false),
+            None => (Manifest::new(cnf, LEGACY_FORMAT_VERSION), false),
+        })
+    }
+
+    /// 打开工作目录时调用，检查磁盘格式是否是当前程序支持的，非只读模式会补写缺少的描述文件
+    pub fn load_or_init(cnf: &Config) -> CustomResult<Manifest> {
+        let (manifest, exists) = Manifest::read_or_detect(cnf)?;
+        if !exists && !cnf.read_only {
+            manifest.write(&cnf.workspace)?;
+            log::info!("写入描述文件:{}，磁盘格式版本:{}", get_manifest_file_name(&cnf.workspace), manifest.format_version);
+        }
+        if manifest.format_version > FORMAT_VERSION {
+            return Err(unsupported_format_err(format!("workspace[{}]的磁盘格式版本是{}，当前程序最高只支持{}，请升级程序",
+                                                      cnf.workspace, manifest.format_version, FORMAT_VERSION)));
+        }
+        if manifest.format_version < FORMAT_VERSION || manifest.upgrading_to.is_some() {
+            return Err(needs_upgrade_err(format!("workspace[{}]的磁盘格式版本是{}，需要先执行 learn-db upgrade --workspace {} 升级到{}",
+                                                 cnf.workspace, manifest.format_version, cnf.workspace, FORMAT_VERSION)));
+        }
+        Ok(manifest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::custom_err::ErrorKind;
+    use crate::store::{get_log_file_name, get_manifest_file_name};
+    use crate::store::manifest::{FORMAT_VERSION, LEGACY_FORMAT_VERSION, Manifest};
+    use crate::test_util::test_workspace;
+
+    #[test]
+    pub fn test_load_or_init() {
+        let workspace = test_workspace("manifest");
+        let mut cnf = Config::new(workspace.to_string());
+
+        // 只读模式不会写描述文件
+        cnf.read_only = true;
+        assert_eq!(Manifest::load_or_init(&cnf).unwrap().format_version, FORMAT_VERSION);
+        assert!(Manifest::read(&cnf.workspace).unwrap().is_none());
+
+        cnf.read_only = false;
+        let manifest = Manifest::load_or_init(&cnf).unwrap();
+        assert_eq!(Manifest::read(&cnf.workspace).unwrap(), Some(manifest.clone()));
+        // 再次打开时使用已有的描述文件
+        assert_eq!(Manifest::load_or_init(&cnf).unwrap(), manifest);
+
+        let mut newer = manifest;
+        newer.format_version = FORMAT_VERSION + 1;
+        newer.write(&cnf.workspace).unwrap();
+        assert_eq!(Manifest::load_or_init(&cnf).unwrap_err().kind, ErrorKind::UnsupportedFormat);
+
+        std::fs::write(get_manifest_file_name(&cnf.workspace), "not json").unwrap();
+        assert!(Manifest::load_or_init(&cnf).is_err());
+
+        // 没有描述文件，但是有数据文件，是旧版本创建的目录，需要先升级
+        std::fs::remove_file(get_manifest_file_name(&cnf.workspace)).unwrap();
+        std::fs::write(get_log_file_name(1, &cnf.workspace), "").unwrap();
+        let err = Manifest::load_or_init(&cnf).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::NeedsUpgrade);
+        assert!(err.message.contains("upgrade"));
+        assert_eq!(Manifest::read(&cnf.workspace).unwrap().unwrap().format_version, LEGACY_FORMAT_VERSION);
+    }
+}
@@ -1,25 +1,26 @@
-use std::collections::hash_map::RandomState;
-use std::num::ParseIntError;
+use std::os::unix::fs::FileExt;
 use std::path::Path;
 use std::str::FromStr;
 
-use dashmap::DashMap;
-use dashmap::mapref::one::Ref;
-use tokio::fs::{File, OpenOptions};
-use tokio::io::{AsyncReadExt, AsyncSeekExt, SeekFrom};
+use tokio::io::{AsyncRead, AsyncReadExt};
 
-use crate::custom_err::CustomResult;
+use crate::custom_err::{common_err, CustomResult};
 use crate::http_param::DataItem;
 use crate::index::DataPosition;
+use crate::store::file_pool::{FilePool, ReadHandle};
+use crate::store::record::{HEADER_LEN, Record, RecordHeader};
 
 pub mod write_consumer;
 pub mod data_manager;
-mod compression_task;
-
-lazy_static! {
-    /// This is an example for using doc comment attributes
-    static ref FILE_MAP: DashMap<u32,File> = DashMap::new();
-}
+pub mod manifest;
+pub mod compression_task;
+pub mod jobs;
+pub mod rate_limiter;
+mod file_pool;
+pub mod record;
+pub mod recover_task;
+mod value_cache;
+pub mod workspace_lock;
 
 // 文件前缀
 const FILE_PREFIX: &str = "learn_db_";
@@ -27,6 +28,10 @@ const FILE_PREFIX: &str = "learn_db_";
 const LOG_FILE_SUFFIX: &str = ".log";
 // 索引文件后缀
 const INDEX_FILE_SUFFIX: &str = ".index";
+// 工作目录的锁文件
+const LOCK_FILE_NAME: &str = "LOCK";
+// 工作目录的描述文件
+const MANIFEST_FILE_NAME: &str = "MANIFEST";
 
 /// 按照固定的格式生成文件名
 pub fn get_log_file_name(id: u32, dir: &String) -> String {
@@ -38,11 +43,16 @@ pub fn get_index_file_name(id: u32, dir: &String) -> String {
     format!("{}/{}{}{}", dir, FILE_PREFIX, id, INDEX_FILE_SUFFIX)
 }
 
-/// 按照固定的格式生成文件名
-pub fn get_tmp_index_file_name(id: u32, dir: &String) -> String {
-    format!("{}/tmp_{}{}{}", dir, FILE_PREFIX, id, INDEX_FILE_SUFFIX)
+
+/// 工作目录的锁文件
+pub fn get_lock_file_name(dir: &String) -> String {
+    format!("{}/{}", dir, LOCK_FILE_NAME)
 }
 
+/// 工作目录的描述文件
+pub fn get_manifest_file_name(dir: &String) -> String {
+    format!("{}/{}", dir, MANIFEST_FILE_NAME)
+}
 
 /// 指定的文件，是否是日志文件
 pub fn is_log_file(path: &Path) -> bool {
@@ -68,34 +78,97 @@ pub fn get_file_id_from_path(path: &Path) -> u32 {
     0
 }
 
+/// 从索引文件中，解析出对应的文件ID，不是索引文件时返回None
+pub fn get_index_file_id(path: &Path) -> Option<u32> {
+    let name = path.file_name()?.to_str()?;
+    name.strip_prefix(FILE_PREFIX)?.strip_suffix(INDEX_FILE_SUFFIX)?.parse().ok()
+}
+
 // 根据位置信息，读取文件内容
-pub async fn read_by_dp(dir: &String, dp: &DataPosition) -> CustomResult<String> {
-    let mut file = match FILE_MAP.get(&dp.file_id) {
-        None => {
-            let f = OpenOptions::new()
-                .read(true)
-                .open(get_log_file_name(dp.file_id, dir))
-                .await?;
-            FILE_MAP.insert(dp.file_id, f.try_clone().await?);
-            f
-        }
-        Some(f) => {
-            f.value().try_clone().await?
+// use_mmap 为true时，表示该文件已经封存，可以使用mmap读取
+pub async fn read_by_dp(pool: &FilePool, dp: &DataPosition, use_mmap: bool) -> CustomResult<String> {
+    Ok(read_record_by_dp(pool, dp, use_mmap).await?.into_item()?.value)
+}
+
+/// 根据位置信息，读取完整的记录
+pub async fn read_record_by_dp(pool: &FilePool, dp: &DataPosition, use_mmap: bool) -> CustomResult<Record> {
+    let offset = dp.offset as u64;
+    match pool.get(dp.file_id, use_mmap)? {
+        ReadHandle::Mmap(mmap) => Record::decode_at(&mmap, offset),
+        ReadHandle::File(file) => {
+            tokio::task::spawn_blocking(move || read_record_at(&file, offset))
+                .await
+                .map_err(|e| common_err(e.to_string()))?
         }
-    };
+    }
+}
+
+/// 使用pread读取指定位置的一条记录，不会修改文件的读写位置
+fn read_record_at(file: &std::fs::File, offset: u64) -> CustomResult<Record> {
+    let mut header_buf = [0u8; HEADER_LEN];
+    file.read_exact_at(&mut header_buf, offset)?;
+    let header = RecordHeader::decode(&header_buf)?;
+    let mut body = vec![0u8; header.body_len()];
+    file.read_exact_at(&mut body, offset + HEADER_LEN as u64)?;
+    Record::decode(&header, &body)
+}
 
-    file.seek(SeekFrom::Start(dp.offset as u64)).await;
+/// 从指定文件中，读取下一条记录，返回记录占用的字节数和记录
+pub async fn read_record<R: AsyncRead + Unpin>(file: &mut R) -> CustomResult<(u32, Record)> {
+    let mut header_buf = [0u8; HEADER_LEN];
+    file.read_exact(&mut header_buf).await?;
+    let header = RecordHeader::decode(&header_buf)?;
 
-    Ok(read_data_item(&mut file).await?.1.value)
+    let mut body = vec![0u8; header.body_len()];
+    file.read_exact(&mut body).await?;
+
+    let record = Record::decode(&header, &body)?;
+    Ok(((HEADER_LEN + body.len()) as u32, record))
 }
 
 /// 从指定文件中，读取下一条数据
-pub async fn read_data_item(file: &mut File) -> CustomResult<(u32,DataItem)> {
-    let len = file.read_u32().await?;
+pub async fn read_data_item<R: AsyncRead + Unpin>(file: &mut R) -> CustomResult<(u32,DataItem)> {
+    let (len, record) = read_record(file).await?;
+    Ok((len, record.into_item()?))
+}
 
-    let mut buffer = vec![Default::default(); len as usize];
-    file.read(&mut buffer[..]).await;
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::http_param::DataItem;
+    use crate::index::DataPosition;
+    use crate::store::{get_log_file_name, read_by_dp};
+    use crate::store::file_pool::FilePool;
+    use crate::store::record::Record;
+    use crate::test_util::test_workspace;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_read_by_dp() {
+        let workspace = test_workspace("read");
+
+        // 两个文件分别用 pread 和 mmap 读取
+        let mut offsets = Vec::new();
+        for file_id in [1, 2] {
+            let mut buf = Vec::new();
+            for i in 0..100 {
+                offsets.push((file_id, buf.len() as u32, format!("value_{}", i)));
+                Record::new(DataItem { key: format!("key_{}", i), value: format!("value_{}", i) }).encode_into(&mut buf);
+            }
+            std::fs::write(get_log_file_name(file_id, &workspace), buf).unwrap();
+        }
 
-    let item: DataItem = serde_json::from_str(&String::from_utf8(buffer)?)?;
-    Ok((len + 4,item))
+        let pool = Arc::new(FilePool::new(workspace.to_string(), 8));
+        let mut handles = Vec::new();
+        for (file_id, offset, value) in offsets {
+            let pool = pool.clone();
+            handles.push(tokio::spawn(async move {
+                let dp = DataPosition::new(file_id, offset);
+                assert_eq!(read_by_dp(&pool, &dp, file_id == 2).await.unwrap(), value);
+            }));
+        }
+        for handle in handles {
+            handle.await.unwrap();
+        }
+    }
 }
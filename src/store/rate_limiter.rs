@@ -0,0 +1,110 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::time;
+use tokio::time::{Duration, Instant};
+
+// 等待令牌时每次最多睡眠的时间，期间修改的限速可以及时生效
+const MAX_SLEEP: Duration = Duration::from_millis(100);
+
+/// 令牌桶限速，单位是字节/秒，0表示不限速
+/// 桶的容量是一秒的流量，超过容量的请求会先透支，等待补足后再返回，所以单次请求的大小不受限制
+pub struct RateLimiter {
+    rate: AtomicU64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    // 剩余的令牌，透支时为负数
+    tokens: f64,
+    // 上一次补充令牌的时间
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> RateLimiter {
+        RateLimiter {
+            rate: AtomicU64::new(rate),
+            bucket: Mutex::new(Bucket { tokens: rate as f64, last: Instant::now() }),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    /// 运行时修改限速，正在等待的请求按新的速率继续等待，改成0时马上返回，并清空透支的令牌
+    pub fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+        if rate == 0 {
+            let mut bucket = self.bucket.lock().unwrap();
+            bucket.tokens = bucket.tokens.max(0.0);
+            bucket.last = Instant::now();
+        }
+    }
+
+    /// 申请指定字节数的令牌，令牌不够时等待
+    /// 只在扣减令牌时持有锁，后来的请求透支得更多，等待的时间也更长，所以仍然是先到先得
+    pub async fn acquire(&self, bytes: u64) {
+        let rate = self.rate();
+        if rate == 0 {
+            return;
+        }
+        let mut debt = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.last).as_secs_f64() * rate as f64;
+            bucket.tokens = (bucket.tokens + refill).min(rate as f64) - bytes as f64;
+            bucket.last = now;
+            -bucket.tokens
+        };
+        // 透支的部分分段等待，每段都按当前的速率计算，按实际睡眠的时间扣减
+        loop {
+            let rate = self.rate();
+            if rate == 0 {
+                return;
+            }
+            let wait = Duration::from_secs_f64(debt.max(0.0) / rate as f64).min(MAX_SLEEP);
+            if wait.is_zero() {
+                return;
+            }
+            let start = Instant::now();
+            time::sleep(wait).await;
+            debt -= start.elapsed().as_secs_f64() * rate as f64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::time;
+    use tokio::time::{Duration, Instant};
+
+    use crate::store::rate_limiter::RateLimiter;
+
+    #[tokio::test]
+    async fn test_rate_limiter() {
+        // 桶里有一秒的令牌，第一次不用等，之后按速率等待
+        let limiter = RateLimiter::new(10_000);
+        let start = Instant::now();
+        limiter.acquire(10_000).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+        limiter.acquire(1_000).await;
+        limiter.acquire(2_000).await;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(280) && elapsed < Duration::from_millis(600), "{:?}", elapsed);
+
+        // 改成不限速后马上生效，正在等待的请求也会返回
+        let limiter = Arc::new(RateLimiter::new(1));
+        let waiting = limiter.clone();
+        let handle = tokio::spawn(async move { waiting.acquire(1_000).await });
+        time::sleep(Duration::from_millis(50)).await;
+        let start = Instant::now();
+        limiter.set_rate(0);
+        handle.await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(200), "{:?}", start.elapsed());
+        limiter.acquire(u64::MAX).await;
+    }
+}
@@ -0,0 +1,164 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::custom_err::{corruption_err, CustomResult};
+use crate::http_param::DataItem;
+
+// 记录头的长度：crc32(4) + 时间戳(8) + 标记(1) + key长度(4) + value长度(4)
+pub const HEADER_LEN: usize = 21;
+// 单条记录key和value加起来的最大长度，超过说明记录头已经损坏
+pub const MAX_BODY_LEN: usize = 1 << 30;
+
+/// 数据文件中的一条记录，格式（大端）：
+/// | crc32 | 时间戳(毫秒) | 标记 | key长度 | value长度 | key | value |
+/// crc32 校验的是除自身以外的全部内容
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    // 写入时间，unix时间戳（毫秒）
+    pub timestamp: u64,
+    // 预留的标记位，目前都是0
+    pub flags: u8,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+/// 记录头，先读出记录头才知道整条记录的长度
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordHeader {
+    pub crc: u32,
+    pub timestamp: u64,
+    pub flags: u8,
+    pub key_len: u32,
+    pub value_len: u32,
+}
+
+impl RecordHeader {
+    pub fn decode(buf: &[u8]) -> CustomResult<RecordHeader> {
+        let buf = buf.get(..HEADER_LEN)
+            .ok_or(corruption_err(format!("记录头长度不足{}字节", HEADER_LEN)))?;
+        let header = RecordHeader {
+            crc: u32::from_be_bytes(buf[0..4].try_into().unwrap()),
+            timestamp: u64::from_be_bytes(buf[4..12].try_into().unwrap()),
+            flags: buf[12],
+            key_len: u32::from_be_bytes(buf[13..17].try_into().unwrap()),
+            value_len: u32::from_be_bytes(buf[17..21].try_into().unwrap()),
+        };
+        if header.body_len() > MAX_BODY_LEN {
+            return Err(corruption_err(format!("记录长度{}超过上限，记录头已损坏", header.body_len())));
+        }
+        Ok(header)
+    }
+
+    /// key和value的总长度
+    pub fn body_len(&self) -> usize {
+        self.key_len as usize + self.value_len as usize
+    }
+
+    fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut buf = [0u8; HEADER_LEN];
+        buf[0..4].copy_from_slice(&self.crc.to_be_bytes());
+        buf[4..12].copy_from_slice(&self.timestamp.to_be_bytes());
+        buf[12] = self.flags;
+        buf[13..17].copy_from_slice(&self.key_len.to_be_bytes());
+        buf[17..21].copy_from_slice(&self.value_len.to_be_bytes());
+        buf
+    }
+}
+
+impl Record {
+    /// 用当前时间生成一条记录
+    pub fn new(item: DataItem) -> Record {
+        Record {
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0),
+            flags: 0,
+            key: item.key.into_bytes(),
+            value: item.value.into_bytes(),
+        }
+    }
+
+    /// 编码后占用的字节数
+    pub fn encoded_len(&self) -> usize {
+        HEADER_LEN + self.key.len() + self.value.len()
+    }
+
+    /// 编码后追加到buf的末尾
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        let mut header = RecordHeader {
+            crc: 0,
+            timestamp: self.timestamp,
+            flags: self.flags,
+            key_len: self.key.len() as u32,
+            value_len: self.value.len() as u32,
+        };
+        header.crc = checksum(&header.encode(), &self.key, &self.value);
+        buf.extend_from_slice(&header.encode());
+        buf.extend_from_slice(&self.key);
+        buf.extend_from_slice(&self.value);
+    }
+
+    /// 根据记录头和读出的key、value还原记录，校验不通过时返回错误
+    pub fn decode(header: &RecordHeader, body: &[u8]) -> CustomResult<Record> {
+        if body.len() != header.body_len() {
+            return Err(corruption_err(format!("记录长度不一致，期望{}，实际{}", header.body_len(), body.len())));
+        }
+        let (key, value) = body.split_at(header.key_len as usize);
+        let crc = checksum(&header.encode(), key, value);
+        if crc != header.crc {
+            return Err(corruption_err(format!("记录校验失败，期望{:08x}，实际{:08x}", header.crc, crc)));
+        }
+        Ok(Record {
+            timestamp: header.timestamp,
+            flags: header.flags,
+            key: key.to_vec(),
+            value: value.to_vec(),
+        })
+    }
+
+    /// 从一段内存中解析指定位置的一条记录
+    pub fn decode_at(data: &[u8], offset: u64) -> CustomResult<Record> {
+        let start = offset as usize;
+        let header = RecordHeader::decode(data.get(start..)
+            .ok_or(corruption_err(format!("数据位置{}超出文件范围", offset)))?)?;
+        let body = data.get(start + HEADER_LEN..start + HEADER_LEN + header.body_len())
+            .ok_or(corruption_err(format!("数据位置{}超出文件范围", offset)))?;
+        Record::decode(&header, body)
+    }
+
+    pub fn into_item(self) -> CustomResult<DataItem> {
+        Ok(DataItem {
+            key: String::from_utf8(self.key)?,
+            value: String::from_utf8(self.value)?,
+        })
+    }
+}
+
+/// 计算校验值，不包含记录头中crc自身的4个字节
+fn checksum(header: &[u8; HEADER_LEN], key: &[u8], value: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[4..]);
+    hasher.update(key);
+    hasher.update(value);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http_param::DataItem;
+    use crate::store::record::{HEADER_LEN, Record};
+
+    #[test]
+    pub fn test_encode_decode() {
+        let record = Record::new(DataItem { key: String::from("name"), value: String::from("杨") });
+        let mut buf = vec![0u8; 3];
+        record.encode_into(&mut buf);
+        assert_eq!(buf.len(), 3 + record.encoded_len());
+        assert_eq!(Record::decode_at(&buf, 3).unwrap(), record);
+
+        // 任意一个字节损坏都能发现
+        for i in 3..buf.len() {
+            let mut corrupted = buf.clone();
+            corrupted[i] ^= 0x01;
+            assert!(Record::decode_at(&corrupted, 3).is_err(), "byte {}", i);
+        }
+        assert!(Record::decode_at(&buf[..HEADER_LEN], 0).is_err());
+    }
+}
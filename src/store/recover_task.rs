@@ -0,0 +1,277 @@
+use std::cmp::max;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use serde::Serialize;
+use tokio::task::JoinHandle;
+
+use crate::calc_hash;
+use crate::config::Config;
+use crate::custom_err::{common_err, CustomResult};
+use crate::index::DataPosition;
+use crate::index::dynamic_index::DynamicParallelIndexWrapper;
+use crate::store::{get_index_file_name, get_log_file_name};
+use crate::store::compression_task::{scan_file_id_vec, scan_log_index, write_index_file};
+
+/// 索引恢复的进度，HTTP服务先于恢复启动，恢复期间可以通过 /ready 查看
+#[derive(Default)]
+pub struct RecoverProgress {
+    // 需要恢复的文件总数
+    total_files: AtomicU32,
+    // 已经合并进索引的文件数
+    finished_files: AtomicU32,
+    // 已经合并进索引的条数（包含被覆盖的旧数据）
+    loaded_entries: AtomicU64,
+    // 是否全部恢复完成
+    done: AtomicBool,
+}
+
+/// 恢复进度的快照
+#[derive(Serialize, Debug)]
+pub struct RecoverProgressView {
+    pub total_files: u32,
+    pub finished_files: u32,
+    pub loaded_entries: u64,
+    pub done: bool,
+}
+
+impl RecoverProgress {
+    pub fn view(&self) -> RecoverProgressView {
+        RecoverProgressView {
+            total_files: self.total_files.load(Ordering::SeqCst),
+            finished_files: self.finished_files.load(Ordering::SeqCst),
+            loaded_entries: self.loaded_entries.load(Ordering::SeqCst),
+            done: self.done.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// 从磁盘中恢复索引
+/// 1. 多个文件的索引并发读取，最多同时读取 cnf.recover_parallel 个文件
+/// 2. 读取结果严格按照 file_id 从小到大合并，保证新数据覆盖旧数据
+/// 3. 单个文件内部，按照key的hash分片后并发合并，同一个key总在同一个分片里，所以顺序不会乱
+pub async fn recover_index_from_disk(cnf: &Config, progress: &RecoverProgress) -> CustomResult<DynamicParallelIndexWrapper> {
+    log::info!("开始从磁盘恢复索引...");
+
+    let workspace = &cnf.workspace;
+    let file_id_vec = scan_file_id_vec(workspace);
+    progress.total_files.store(file_id_vec.len() as u32, Ordering::SeqCst);
+
+    let index = DynamicParallelIndexWrapper::new(estimate_parallel(workspace, &file_id_vec));
+
+    // 按 file_id 顺序启动读取任务，最多同时有 recover_parallel 个文件在读取或者等待合并，
+    // 最早的文件合并完成后才启动下一个，所以内存中最多只有 recover_parallel 个文件的索引，并且不依赖任务的调度顺序
+    let parallel = max(cnf.recover_parallel, 1);
+    let mut file_ids = file_id_vec.into_iter();
+    let mut handles: VecDeque<(u32, JoinHandle<CustomResult<LoadedIndex>>)> = VecDeque::with_capacity(parallel);
+    loop {
+        while handles.len() < parallel {
+            let file_id = match file_ids.next() {
+                None => break,
+                Some(file_id) => file_id,
+            };
+            let workspace = workspace.clone();
+            let read_only = cnf.read_only;
+            handles.push_back((file_id, tokio::spawn(async move {
+                load_index_file(file_id, &workspace, read_only).await
+            })));
+        }
+        let (file_id, handle) = match handles.pop_front() {
+            None => break,
+            Some(next) => next,
+        };
+
+        let loaded = match handle.await.map_err(|e| common_err(e.to_string())).and_then(|res| res) {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                // 一个文件失败后整个恢复失败，不用再等其它文件读完
+                for (_, handle) in handles {
+                    handle.abort();
+                }
+                return Err(e);
+            }
+        };
+        let entries = merge_into_index(&index, file_id, loaded.entries, loaded.file_len,
+                                       cnf.recover_parallel).await?;
+
+        let finished = progress.finished_files.fetch_add(1, Ordering::SeqCst) + 1;
+        let loaded_entries = progress.loaded_entries.fetch_add(entries, Ordering::SeqCst) + entries;
+        log::info!("索引文件{}恢复完成，进度 {}/{}，累计加载 {} 条",
+            get_index_file_name(file_id, workspace),
+            finished, progress.total_files.load(Ordering::SeqCst), loaded_entries);
+    }
+
+    progress.done.store(true, Ordering::SeqCst);
+    log::info!("索引恢复完成，共 {} 个key", index.size().await);
+    Ok(index)
+}
+
+/// 已经读取到内存中的单个索引文件
+struct LoadedIndex {
+    entries: Vec<(String, u32)>,
+    // 数据文件的长度，用来计算最后一条记录的长度
+    file_len: u32,
+}
+
+/// 读取单个数据文件对应的索引，索引文件不存在时先生成
+/// 只读模式不能修改工作目录，只扫描数据文件
+async fn load_index_file(file_id: u32, workspace: &String, read_only: bool) -> CustomResult<LoadedIndex> {
+    let index_file_name = get_index_file_name(file_id, workspace);
+    let log_file_name = get_log_file_name(file_id, workspace);
+    let file_len = tokio::fs::metadata(&log_file_name).await?.len() as u32;
+    if !Path::new(&index_file_name).exists() {
+        let (entries, damage) = scan_log_index(Path::new(&log_file_name), None).await?;
+        match damage {
+            // 数据文件中间损坏时不生成索引文件，只加载能读出的部分，下次启动会重新扫描
+            Some(e) => log::error!("{}，不生成索引文件", e),
+            None if !read_only => write_index_file(Path::new(&index_file_name), &entries).await?,
+            None => {}
+        }
+        return Ok(LoadedIndex { entries, file_len });
+    }
+
+    let entries = tokio::task::spawn_blocking(move || read_index_file(Path::new(&index_file_name)))
+        .await
+        .map_err(|e| common_err(e.to_string()))??;
+    Ok(LoadedIndex { entries, file_len })
+}
+
+/// 把单个文件的索引合并到内存索引中，返回合并的条数
+/// 索引按记录在文件中的顺序排列，每条记录的长度就是到下一条记录（或文件末尾）的距离
+async fn merge_into_index(index: &DynamicParallelIndexWrapper, file_id: u32, entries: Vec<(String, u32)>,
+                          file_len: u32, parallel: usize) -> CustomResult<u64> {
+    let parallel = max(parallel, 1);
+    let total = entries.len() as u64;
+
+    let ends: Vec<u32> = entries.iter().skip(1).map(|(_, offset)| *offset).chain(Some(file_len)).collect();
+    let mut shards = vec![Vec::new(); parallel];
+    for ((key, offset), end) in entries.into_iter().zip(ends) {
+        let dp = DataPosition::new(file_id, offset).with_len(end.saturating_sub(offset));
+        shards[(calc_hash(&key) % parallel as u64) as usize].push((key, dp));
+    }
+
+    let mut handles = Vec::with_capacity(parallel);
+    for shard in shards {
+        let index = index.clone();
+        handles.push(tokio::spawn(async move {
+            for (key, dp) in shard {
+                index.push(&key, dp).await;
+            }
+        }));
+    }
+    for handle in handles {
+        handle.await.map_err(|e| common_err(e.to_string()))?;
+    }
+    Ok(total)
+}
+
+/// 读取索引文件的全部内容，文件格式：key长度(u32) + key + 偏移量(u32)
+pub fn read_index_file(index_path: &Path) -> CustomResult<Vec<(String, u32)>> {
+    let mut reader = BufReader::new(File::open(index_path)?);
+    let mut entries = Vec::new();
+    loop {
+        let mut len_buf = [0u8; 4];
+        match reader.read_exact(&mut len_buf) {
+            Ok(_) => {}
+            // 正常读到了文件末尾
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let mut key_buf = vec![0u8; u32::from_be_bytes(len_buf) as usize];
+        reader.read_exact(&mut key_buf)?;
+        let mut offset_buf = [0u8; 4];
+        reader.read_exact(&mut offset_buf)?;
+        entries.push((String::from_utf8(key_buf)?, u32::from_be_bytes(offset_buf)));
+    }
+    Ok(entries)
+}
+
+/// 根据索引文件的大小，预估需要的并行度，避免恢复期间链表过长
+/// 没有索引文件的数据文件，按数据文件大小估算
+fn estimate_parallel(workspace: &String, file_id_vec: &[u32]) -> u64 {
+    let mut bytes = 0;
+    for file_id in file_id_vec {
+        let index_meta = std::fs::metadata(get_index_file_name(*file_id, workspace));
+        if let Ok(meta) = index_meta.or_else(|_| std::fs::metadata(get_log_file_name(*file_id, workspace))) {
+            bytes += meta.len();
+        }
+    }
+    // 每条索引按32字节估算，每个链表平均放8条，和扩容的阙值保持一致
+    max(8, bytes / 32 / 8)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::index::DataPosition;
+    use crate::store::recover_task::{recover_index_from_disk, RecoverProgress};
+    use crate::store::{get_index_file_name, get_log_file_name};
+    use crate::store::compression_task::generate_index_file;
+    use crate::store::record::Record;
+    use crate::http_param::DataItem;
+    use crate::test_util::test_workspace;
+
+    fn write_index_file(path: &String, entries: &[(&str, u32)]) {
+        let mut buf = Vec::new();
+        for (key, offset) in entries {
+            buf.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            buf.extend_from_slice(key.as_bytes());
+            buf.extend_from_slice(&offset.to_be_bytes());
+        }
+        std::fs::write(path, buf).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_recover_newer_file_wins() {
+        let workspace = test_workspace("recover");
+
+        for file_id in 1..=5u32 {
+            // 数据文件只用来扫描 file_id，索引文件已经存在，不会被读取
+            std::fs::write(crate::store::get_log_file_name(file_id, &workspace), b"").unwrap();
+            write_index_file(&get_index_file_name(file_id, &workspace),
+                             &[("same", file_id), ("same", file_id * 10), ("only_1", 1)]);
+        }
+
+        let mut cnf = Config::new(workspace.to_string());
+        cnf.recover_parallel = 2;
+        let progress = RecoverProgress::default();
+        let index = recover_index_from_disk(&cnf, &progress).await.unwrap();
+
+        assert_eq!(index.find(&String::from("same")).await, Some(DataPosition::new(5, 50)));
+        assert_eq!(index.find(&String::from("only_1")).await, Some(DataPosition::new(5, 1)));
+        let view = progress.view();
+        assert!(view.done);
+        assert_eq!(view.finished_files, 5);
+        assert_eq!(view.loaded_entries, 15);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_recover_damaged_log_file() {
+        let workspace = test_workspace("recover-damaged");
+
+        // 第二条记录损坏，第三条还是完整的
+        let mut buf = Vec::new();
+        let mut offsets = Vec::new();
+        for i in 0..3 {
+            offsets.push(buf.len() as u32);
+            Record::new(DataItem { key: format!("key_{}", i), value: format!("value_{}", i) }).encode_into(&mut buf);
+        }
+        buf[offsets[1] as usize + 30] ^= 0x01;
+        let log_file_name = get_log_file_name(1, &workspace);
+        let index_file_name = get_index_file_name(1, &workspace);
+        std::fs::write(&log_file_name, &buf).unwrap();
+
+        // 不会生成只包含前半部分的索引文件
+        assert!(generate_index_file(std::path::Path::new(&log_file_name), std::path::Path::new(&index_file_name)).await.is_err());
+        assert!(!std::path::Path::new(&index_file_name).exists());
+
+        let cnf = Config::new(workspace.to_string());
+        let index = recover_index_from_disk(&cnf, &RecoverProgress::default()).await.unwrap();
+        assert_eq!(index.find(&String::from("key_0")).await.map(|dp| dp.offset), Some(0));
+        assert!(!std::path::Path::new(&index_file_name).exists());
+        assert_eq!(std::fs::read(&log_file_name).unwrap(), buf);
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+use crate::index::DataPosition;
+
+// 每个缓存条目除了value以外，额外占用的内存估算
+const ENTRY_OVERHEAD: u64 = 64;
+
+/// 热点数据的读缓存，按字节数限制容量，超过后淘汰最久没有访问的数据
+/// 以 DataPosition 作为key，数据被覆盖后位置会变化，所以旧的缓存自然就不会再被访问到
+pub struct ValueCache {
+    // 最多缓存多少字节
+    capacity: u64,
+    inner: Mutex<LruInner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct LruInner {
+    // 位置 -> (value, 最近一次访问的序号)
+    map: HashMap<DataPosition, (String, u64)>,
+    // 访问序号 -> 位置，序号最小的就是最久没有访问的
+    order: BTreeMap<u64, DataPosition>,
+    // 当前已经使用的字节数
+    used: u64,
+    // 单调递增的访问序号
+    tick: u64,
+}
+
+/// 缓存的统计信息
+#[derive(Serialize, Debug)]
+pub struct CacheStats {
+    pub capacity_bytes: u64,
+    pub used_bytes: u64,
+    pub entries: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl ValueCache {
+    pub fn new(capacity: u64) -> ValueCache {
+        ValueCache {
+            capacity,
+            inner: Mutex::new(LruInner {
+                map: HashMap::new(),
+                order: BTreeMap::new(),
+                used: 0,
+                tick: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// 查询缓存，命中时刷新访问顺序
+    pub fn get(&self, dp: &DataPosition) -> Option<String> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let LruInner { map, order, .. } = &mut *inner;
+        match map.get_mut(dp) {
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            Some((value, last)) => {
+                order.remove(last);
+                order.insert(tick, dp.clone());
+                *last = tick;
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(value.clone())
+            }
+        }
+    }
+
+    /// 放入缓存，单条超过容量的数据不缓存
+    pub fn put(&self, dp: DataPosition, value: String) {
+        let size = value.len() as u64 + ENTRY_OVERHEAD;
+        if size > self.capacity {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        if let Some((old, last)) = inner.map.insert(dp.clone(), (value, tick)) {
+            inner.order.remove(&last);
+            inner.used -= old.len() as u64 + ENTRY_OVERHEAD;
+        }
+        inner.order.insert(tick, dp);
+        inner.used += size;
+
+        while inner.used > self.capacity {
+            let (_, oldest) = match inner.order.pop_first() {
+                None => break,
+                Some(v) => v,
+            };
+            if let Some((old, _)) = inner.map.remove(&oldest) {
+                inner.used -= old.len() as u64 + ENTRY_OVERHEAD;
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock().unwrap();
+        CacheStats {
+            capacity_bytes: self.capacity,
+            used_bytes: inner.used,
+            entries: inner.map.len() as u64,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::index::DataPosition;
+    use crate::store::value_cache::{ENTRY_OVERHEAD, ValueCache};
+
+    #[test]
+    pub fn test_evict_least_recently_used() {
+        let cache = ValueCache::new(3 * (ENTRY_OVERHEAD + 1));
+        cache.put(DataPosition::new(1, 0), String::from("a"));
+        cache.put(DataPosition::new(1, 5), String::from("b"));
+        cache.put(DataPosition::new(1, 10), String::from("c"));
+        // 访问一次，a 变成最新的
+        assert_eq!(cache.get(&DataPosition::new(1, 0)), Some(String::from("a")));
+
+        cache.put(DataPosition::new(2, 0), String::from("d"));
+        assert_eq!(cache.get(&DataPosition::new(1, 5)), None);
+        assert_eq!(cache.get(&DataPosition::new(1, 0)), Some(String::from("a")));
+        assert_eq!(cache.get(&DataPosition::new(2, 0)), Some(String::from("d")));
+
+        let stats = cache.stats();
+        assert_eq!(stats.entries, 3);
+        assert_eq!(stats.used_bytes, 3 * (ENTRY_OVERHEAD + 1));
+        assert_eq!(stats.hits, 3);
+        assert_eq!(stats.misses, 1);
+    }
+}
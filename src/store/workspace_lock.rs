@@ -0,0 +1,59 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use fs2::FileExt;
+
+use crate::custom_err::{conflict_err, CustomResult};
+use crate::store::get_lock_file_name;
+
+/// 工作目录的锁，同一个工作目录同时只允许一个进程打开写入
+/// 锁文件中记录了持有锁的进程ID，进程退出或者锁被释放后，其它进程才能获取
+pub struct WorkspaceLock {
+    // 持有文件才能持有锁，关闭文件时锁自动释放
+    _file: File,
+}
+
+impl WorkspaceLock {
+    /// 获取锁，已经被其它进程持有时返回错误，错误信息中带上持有锁的进程ID
+    pub fn acquire(workspace: &String) -> CustomResult<WorkspaceLock> {
+        let lock_file_name = get_lock_file_name(workspace);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_file_name)?;
+
+        if file.try_lock_exclusive().is_err() {
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            return Err(conflict_err(format!("workspace[{}]已经被进程[{}]打开，锁文件:{}",
+                                          workspace, pid.trim(), lock_file_name)));
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(std::process::id().to_string().as_bytes())?;
+        file.sync_data()?;
+        log::info!("获取工作目录锁成功:{}", lock_file_name);
+        Ok(WorkspaceLock { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::workspace_lock::WorkspaceLock;
+    use crate::test_util::test_workspace;
+
+    #[test]
+    pub fn test_lock_is_exclusive() {
+        let workspace = test_workspace("lock");
+
+        let lock = WorkspaceLock::acquire(&workspace).unwrap();
+        let err = WorkspaceLock::acquire(&workspace).err().unwrap();
+        assert!(err.message.contains(&std::process::id().to_string()));
+
+        drop(lock);
+        assert!(WorkspaceLock::acquire(&workspace).is_ok());
+    }
+}
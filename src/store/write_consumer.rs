@@ -1,63 +1,156 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
 use tokio::fs::{File, OpenOptions};
-use tokio::io::AsyncWriteExt;
-use tokio::sync::mpsc::error::TryRecvError;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, SeekFrom};
 use tokio::sync::mpsc::Receiver;
+use tokio::sync::watch;
 use tokio::sync::oneshot::Sender as Callback;
+use tokio::task::JoinHandle;
 use tokio::time;
+use tokio::time::Instant;
 
 use crate::custom_err::CustomResult;
 use crate::http_param::DataItem;
 use crate::index::DataPosition;
 use crate::index::dynamic_index::DynamicParallelIndexWrapper;
+use crate::metrics::Histogram;
 use crate::store::get_log_file_name;
-use crate::Config;
+use crate::store::record::Record;
+use crate::config::{Config, Durability};
 
 /// 启动写入消费者
-pub fn start_write_consumer(cnf: Config, max_file_id: u32,
+/// active_file 记录当前正在写入的文件和写入位置，比它小的文件都已经封存，启动时从 active_file 中的文件开始写入
+/// rotate_recv 收到请求后马上切换到新文件，回执新文件的id
+/// 收到关闭信号后不再接收新的写入，队列中已有的数据全部写入并落盘后，任务结束
+pub fn start_write_consumer(cnf: Config,
                             mut recv: Receiver<WriteEvent>,
-                            index: DynamicParallelIndexWrapper) {
+                            mut rotate_recv: Receiver<Callback<CustomResult<u32>>>,
+                            index: DynamicParallelIndexWrapper,
+                            active_file: Arc<ActiveFile>,
+                            metrics: Arc<WriteMetrics>,
+                            mut shutdown: watch::Receiver<bool>) -> JoinHandle<()> {
     tokio::spawn(async move {
         log::info!("写入消费者已启动!");
-        let mut data_file = WriteableFile::new(max_file_id, &cnf.workspace).await.unwrap();
+        let mut closing = false;
+        let mut data_file = WriteableFile::new(active_file.id.load(Ordering::SeqCst), &cnf.workspace, metrics.clone()).await.unwrap();
+        active_file.store(&data_file);
 
         loop {
             // 文件超过最大尺寸时，切换写的新入点
             if data_file.offset > cnf.max_file_size {
-                data_file = WriteableFile::new(data_file.id + 1, &cnf.workspace).await.unwrap();
+                if let Err(e) = data_file.rotate(&cnf.workspace, &active_file).await {
+                    log::error!("切换数据文件失败,{:?}", e);
+                }
             }
 
-            // 批量写入，减少同步次数
-            let batch_size = 100;
-            let mut vec = Vec::new();
+            // 阻塞等待第一条数据；按时间间隔落盘时，到时间了就先落盘
+            let deadline = match cnf.durability {
+                Durability::Interval(ms) if data_file.dirty || !data_file.pending.is_empty() => {
+                    Some(data_file.last_sync + time::Duration::from_millis(ms))
+                }
+                _ => None,
+            };
+            let first = tokio::select! {
+                event = recv.recv() => event,
+                _ = time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    if let Err(e) = data_file.sync().await {
+                        log::error!("数据文件落盘失败,{:?}", e);
+                    }
+                    continue;
+                }
+                // 正在写入的文件是空的，不需要切换
+                Some(callback) = rotate_recv.recv() => {
+                    let res = if data_file.offset == 0 {
+                        Ok(data_file.id)
+                    } else {
+                        data_file.rotate(&cnf.workspace, &active_file).await.map(|_| data_file.id)
+                    };
+                    let _ = callback.send(res);
+                    continue;
+                }
+                // 关闭队列后，已经在队列中的数据还可以继续读取，读完后 recv 返回 None
+                _ = shutdown.changed(), if !closing => {
+                    log::info!("收到关闭信号，开始处理队列中剩余的数据");
+                    recv.close();
+                    closing = true;
+                    continue;
+                }
+            };
+            let first = match first {
+                None => {
+                    log::warn!("write channel 关闭，写线程结束！");
+                    if let Err(e) = data_file.sync().await {
+                        log::error!("数据文件落盘失败,{:?}", e);
+                    }
+                    return;
+                }
+                Some(event) => event,
+            };
 
-            for _ in 0..batch_size {
+            // 把已经到达的数据一起取出来，批量写入，减少同步次数
+            let mut batch_bytes = first.size();
+            let mut vec = vec![first];
+            while vec.len() < cnf.write_batch_size && batch_bytes < cnf.write_batch_bytes {
                 match recv.try_recv() {
                     Ok(event) => {
+                        batch_bytes += event.size();
                         vec.push(event);
                     }
-                    Err(err) => {
-                        match err {
-                            TryRecvError::Empty => {
-                                break;
-                            }
-                            TryRecvError::Disconnected => {
-                                log::warn!("write channel 关闭，写线程结束！");
-                                return;
-                            }
-                        }
-                    }
+                    // 通道关闭的情况，下一轮 recv 时再处理
+                    Err(_) => break,
                 }
             }
-            if vec.is_empty() {
-                // 说明当前写入请求不多，休眠一段时间
-                time::sleep(time::Duration::from_millis(100)).await
-            } else {
-                log::info!("开始处理写入，size={}", vec.len());
 
-                data_file.append(vec, &index).await;
+            log::debug!("开始处理写入，size={}", vec.len());
+            metrics.batch_size.observe(vec.len() as u64);
+            let start = Instant::now();
+            if let Err(e) = data_file.append(vec, &index, cnf.durability).await {
+                log::error!("写入数据失败,{:?}", e);
             }
+            active_file.store(&data_file);
+            metrics.commit_latency.observe(start.elapsed().as_micros() as u64);
+        }
+    })
+}
+
+/// 当前正在写入的文件
+#[derive(Default)]
+pub struct ActiveFile {
+    pub id: AtomicU32,
+    // 已经写入的数据量
+    pub offset: AtomicU32,
+}
+
+impl ActiveFile {
+    fn store(&self, file: &WriteableFile) {
+        self.id.store(file.id, Ordering::SeqCst);
+        self.offset.store(file.offset, Ordering::SeqCst);
+    }
+}
+
+// 耗时直方图的区间上限，单位微秒
+pub const LATENCY_BOUNDS_US: [u64; 12] = [100, 250, 500, 1000, 2500, 5000, 10000, 25000, 50000, 100000, 250000, 1000000];
+
+/// 写入消费者的统计信息
+pub struct WriteMetrics {
+    // 每批写入的条数
+    pub batch_size: Histogram,
+    // 每批写入（包括落盘）的耗时，单位微秒
+    pub commit_latency: Histogram,
+    // 每次落盘的耗时，单位微秒
+    pub fsync_latency: Histogram,
+}
+
+impl WriteMetrics {
+    pub fn new() -> WriteMetrics {
+        WriteMetrics {
+            batch_size: Histogram::new(&[1, 2, 5, 10, 20, 50, 100, 200, 500, 1000]),
+            commit_latency: Histogram::new(&LATENCY_BOUNDS_US),
+            fsync_latency: Histogram::new(&LATENCY_BOUNDS_US),
         }
-    });
+    }
 }
 
 /// 写入事件
@@ -68,6 +161,8 @@ pub struct WriteEvent {
     compare_dp: Option<DataPosition>,
     // 写入完成的回执
     callback: Option<Callback<()>>,
+    // 是否需要落盘后才算写入完成，为空时按照配置的 Durability 处理
+    durable: Option<bool>,
 }
 
 impl WriteEvent {
@@ -76,6 +171,7 @@ impl WriteEvent {
             data_item,
             compare_dp: None,
             callback: None,
+            durable: None,
         }
     }
 
@@ -84,6 +180,7 @@ impl WriteEvent {
             data_item,
             compare_dp: Some(dp),
             callback: None,
+            durable: None,
         }
     }
 
@@ -92,8 +189,24 @@ impl WriteEvent {
             data_item,
             compare_dp: dp,
             callback: Some(callback),
+            durable: None,
         }
     }
+
+    pub fn data_item(&self) -> &DataItem {
+        &self.data_item
+    }
+
+    /// 数据的大小，用来限制每批写入的字节数
+    fn size(&self) -> usize {
+        self.data_item.key.len() + self.data_item.value.len()
+    }
+
+    /// 覆盖配置的落盘策略，true表示必须落盘后才回执，false表示写入后就回执
+    pub fn with_durable(mut self, durable: Option<bool>) -> WriteEvent {
+        self.durable = durable;
+        self
+    }
 }
 
 
@@ -104,69 +217,205 @@ struct WriteableFile {
     file: File,
     // 当前写入了多少数据
     offset: u32,
+    // 是否有还没落盘的数据
+    dirty: bool,
+    // 上一次落盘的时间
+    last_sync: Instant,
+    // 等待落盘后才能回执的请求
+    pending: Vec<Callback<()>>,
+    // 统计落盘耗时
+    metrics: Arc<WriteMetrics>,
 }
 
 impl WriteableFile {
-    async fn new(id: u32, dir: &String) -> CustomResult<WriteableFile> {
+    async fn new(id: u32, dir: &String, metrics: Arc<WriteMetrics>) -> CustomResult<WriteableFile> {
         let file_name = get_log_file_name(id, dir);
         log::info!("打开或创建可写入文件:{}", file_name);
         let f = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(file_name)
             .await?;
         Ok(WriteableFile {
             id,
             file: f,
             offset: 0,
+            dirty: false,
+            last_sync: Instant::now(),
+            pending: Vec::new(),
+            metrics,
         })
     }
 
-    /// 执行写入,并且更新索引
-    async fn append(&mut self, events: Vec<WriteEvent>, index: &DynamicParallelIndexWrapper) -> CustomResult<()> {
-        // todo 这里先写buf，然后一次性写入文件性能会更好，后面再优化
+    /// 切换到下一个文件，切换前当前文件要先落盘
+    async fn rotate(&mut self, dir: &String, active_file: &ActiveFile) -> CustomResult<()> {
+        self.sync().await?;
+        *self = WriteableFile::new(self.id + 1, dir, self.metrics.clone()).await?;
+        active_file.store(self);
+        Ok(())
+    }
 
+    /// 执行写入,并且更新索引
+    /// 整批数据先编码到一个buf中，然后一次性写入文件
+    /// 根据落盘策略决定是否立即落盘，以及什么时候回执
+    async fn append(&mut self, events: Vec<WriteEvent>, index: &DynamicParallelIndexWrapper,
+                    durability: Durability) -> CustomResult<()> {
+        let mut buf = Vec::new();
+        // 本批写入的数据位置，写入文件成功后再更新索引
+        let mut positions: HashMap<String, DataPosition> = HashMap::new();
+        // 写入后就可以回执的请求
         let mut callbacks = Vec::new();
+        // 写入后需要落盘才能回执的请求
+        let mut durable_callbacks = Vec::new();
+        // 本批写入后是否需要立即落盘
+        let mut sync_now = false;
 
         for event in events {
             let data = event.data_item;
 
-            // 处理比较再写入的场景
-            if let Some(dp) = event.compare_dp {
-                match index.find(&data.key).await {
-                    None => {
-                        // 没有索引，说明数据被删除了，不需要写入了
-                        continue;
-                    }
-                    Some(i_dp) => {
-                        if dp != i_dp {
-                            // 说明数据已经更新了，也不需要再写入了
-                            continue;
-                        }
-                    }
-                }
-            }
-
-            let json = serde_json::to_vec(&data)?;
-            let len = json.len() as u32;
-            self.file.write_u32(len).await?;
-            self.file.write(&json).await?;
+            // 处理比较再写入的场景，同一批里前面写入的数据还没有更新索引，所以要先查本批的位置
+            // 没有索引，说明数据被删除了；索引不相等，说明数据已经更新了，这两种都不需要再写入了
+            let skip = match &event.compare_dp {
+                None => false,
+                Some(dp) => match positions.get(&data.key) {
+                    Some(batch_dp) => batch_dp != dp,
+                    None => index.find(&data.key).await.as_ref() != Some(dp),
+                },
+            };
 
-            index.push(&data.key, DataPosition::new(self.id, self.offset)).await;
-
-            self.offset = self.offset + len + 4;
+            if !skip {
+                let offset = self.offset + buf.len() as u32;
+                let key = data.key.clone();
+                Record::new(data).encode_into(&mut buf);
+                let len = self.offset + buf.len() as u32 - offset;
+                positions.insert(key, DataPosition::new(self.id, offset).with_len(len));
+            }
 
+            // 不需要写入的请求也要回执，并且和前面的数据一起回执，保证顺序
+            let durable = event.durable.unwrap_or(durability != Durability::Never);
+            sync_now |= event.durable.unwrap_or(durability == Durability::Always);
             if let Some(callback) = event.callback {
-                callbacks.push(callback);
+                if durable {
+                    durable_callbacks.push(callback);
+                } else {
+                    callbacks.push(callback);
+                }
+            }
+        }
+
+        if !buf.is_empty() {
+            // tokio 的 write_all 把最后一块数据交给后台线程后就返回了，flush 之后数据才真正交给操作系统，
+            // 这时其它句柄才能读到，写入的错误也在这里返回；不落盘的写入没有 sync，必须先 flush 再更新索引和回执
+            let res = match self.file.write_all(&buf).await {
+                Ok(_) => self.file.flush().await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = res {
+                // 写入失败时，截断写了一半的数据，保证文件的写入位置和offset一致
+                self.file.set_len(self.offset as u64).await?;
+                self.file.seek(SeekFrom::Start(self.offset as u64)).await?;
+                return Err(e.into());
+            }
+            self.offset += buf.len() as u32;
+            self.dirty = true;
+            for (key, dp) in positions {
+                index.push(&key, dp).await;
             }
         }
-        self.file.sync_data().await?;
 
         // 处理需要写入完成回执的场景
         for callback in callbacks {
-            callback.send(());
+            let _ = callback.send(());
+        }
+
+        self.pending.append(&mut durable_callbacks);
+        if sync_now {
+            self.sync().await?;
+        }
+        Ok(())
+    }
+
+    /// 落盘，并回执所有等待落盘的请求
+    /// 落盘失败时，等待的请求会收到错误
+    async fn sync(&mut self) -> CustomResult<()> {
+        let pending = std::mem::take(&mut self.pending);
+        if self.dirty {
+            let start = Instant::now();
+            self.file.sync_data().await?;
+            self.metrics.fsync_latency.observe(start.elapsed().as_micros() as u64);
+            self.dirty = false;
+        }
+        self.last_sync = Instant::now();
+        for callback in pending {
+            let _ = callback.send(());
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::oneshot;
+    use tokio::time::timeout;
+
+    use crate::config::{Config, Durability};
+    use crate::http_param::DataItem;
+    use crate::store::data_manager::DataManager;
+    use crate::store::write_consumer::WriteEvent;
+    use crate::test_util::test_workspace;
+
+    fn item(key: &str) -> DataItem {
+        DataItem { key: String::from(key), value: String::from(key) }
+    }
+
+    #[tokio::test]
+    async fn test_callback_waits_for_durability() {
+        let workspace = test_workspace("durability");
+        let mut cnf = Config::new(workspace.to_string());
+        cnf.durability = Durability::Interval(60 * 1000);
+        let dm = DataManager::new(cnf).await.unwrap();
+
+        // 按配置的间隔落盘，间隔很长，所以不会很快回执
+        let (tx, mut pending_rx) = oneshot::channel();
+        dm.push(WriteEvent::new_callback_event(item("a"), None, tx)).await.unwrap();
+        assert!(timeout(Duration::from_millis(500), &mut pending_rx).await.is_err());
+
+        // 不需要落盘的请求，写入后就回执
+        let (tx, rx) = oneshot::channel();
+        dm.push(WriteEvent::new_callback_event(item("b"), None, tx).with_durable(Some(false))).await.unwrap();
+        assert!(timeout(Duration::from_millis(500), rx).await.unwrap().is_ok());
+        // 回执后马上就能从其它句柄读到
+        assert_eq!(dm.find(&String::from("b")).await.unwrap(), Some(String::from("b")));
+
+        // 要求落盘的请求会立即落盘，之前等待的请求也一起回执
+        let (tx, rx) = oneshot::channel();
+        dm.push(WriteEvent::new_callback_event(item("c"), None, tx).with_durable(Some(true))).await.unwrap();
+        assert!(timeout(Duration::from_millis(500), rx).await.unwrap().is_ok());
+        assert!(pending_rx.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_group_commit() {
+        let workspace = test_workspace("group-commit");
+        let dm = DataManager::new(Config::new(workspace.to_string())).await.unwrap();
+
+        for i in 0..1000 {
+            dm.push(WriteEvent::new_simple_event(item(&format!("key_{}", i)))).await.unwrap();
+        }
+        let (tx, rx) = oneshot::channel();
+        dm.push(WriteEvent::new_callback_event(item("last"), None, tx)).await.unwrap();
+        rx.await.unwrap();
+
+        for i in 0..1000 {
+            let key = format!("key_{}", i);
+            assert_eq!(dm.find(&key).await.unwrap(), Some(key));
+        }
+        let batch_size = dm.store_stats().write_batch_size;
+        assert_eq!(batch_size.sum, 1001);
+        assert!(batch_size.count < 1001);
+    }
+}
@@ -0,0 +1,38 @@
+use std::fmt;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// 测试目录的序号，同一个进程中每次调用都不同
+static WORKSPACE_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// 测试用的工作目录，离开作用域时删除
+pub struct TestWorkspace(String);
+
+/// 为测试生成一个新的空目录，目录名带上进程号和序号，并发运行的测试之间、多次运行之间都不会互相影响
+pub fn test_workspace(name: &str) -> TestWorkspace {
+    let dir = std::env::temp_dir().join(format!("learn-db-test-{}-{}-{}",
+                                                name, std::process::id(), WORKSPACE_SEQ.fetch_add(1, Ordering::SeqCst)));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    TestWorkspace(dir.to_str().unwrap().to_string())
+}
+
+impl Deref for TestWorkspace {
+    type Target = String;
+
+    fn deref(&self) -> &String {
+        &self.0
+    }
+}
+
+impl fmt::Display for TestWorkspace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Drop for TestWorkspace {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,196 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use serde::Serialize;
+use tokio::fs::File;
+use tokio::io::BufReader;
+
+use crate::custom_err::{invalid_argument_err, CustomResult};
+use crate::store::{get_index_file_id, is_log_file, read_record};
+use crate::store::recover_task::read_index_file;
+
+#[derive(Args, Debug)]
+pub struct DumpArgs {
+    /// 数据文件（learn_db_N.log）或者索引文件（learn_db_N.index）
+    pub file: PathBuf,
+    /// 只输出指定的key
+    #[arg(long)]
+    pub key: Option<String>,
+    /// 只输出位置大于等于该值的记录
+    #[arg(long)]
+    pub from: Option<u32>,
+    /// 只输出位置小于该值的记录
+    #[arg(long)]
+    pub to: Option<u32>,
+    /// value最多显示的字符数
+    #[arg(long, default_value_t = 64)]
+    pub preview: usize,
+    /// 每条记录输出一行JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// 数据文件中的一条记录
+#[derive(Serialize, Debug)]
+pub struct RecordView {
+    pub offset: u32,
+    pub len: u32,
+    pub timestamp: u64,
+    pub flags: u8,
+    pub key: String,
+    pub value_len: usize,
+    pub value: String,
+}
+
+/// 索引文件中的一条记录
+#[derive(Serialize, Debug)]
+pub struct IndexEntryView {
+    pub key: String,
+    pub offset: u32,
+}
+
+/// 读取到文件末尾之前遇到的错误
+#[derive(Serialize, Debug)]
+pub struct DumpError {
+    pub offset: u32,
+    pub error: String,
+}
+
+impl DumpArgs {
+    fn matches(&self, key: &str, offset: u32) -> bool {
+        self.key.as_ref().map(|k| k == key).unwrap_or(true)
+            && self.from.map(|from| offset >= from).unwrap_or(true)
+            && self.to.map(|to| offset < to).unwrap_or(true)
+    }
+
+    fn print<T: Serialize>(&self, out: &mut impl Write, view: &T, text: String) -> CustomResult<()> {
+        if self.json {
+            writeln!(out, "{}", serde_json::to_string(view)?)?;
+        } else {
+            writeln!(out, "{}", text)?;
+        }
+        Ok(())
+    }
+}
+
+/// 按文件名判断文件类型，输出其中的记录，返回输出的条数
+pub async fn dump(args: &DumpArgs, out: &mut impl Write) -> CustomResult<u64> {
+    if is_log_file(&args.file) {
+        dump_log_file(args, out).await
+    } else if get_index_file_id(&args.file).is_some() {
+        dump_index_file(args, out)
+    } else {
+        Err(invalid_argument_err(format!("{:?}既不是数据文件也不是索引文件", args.file)))
+    }
+}
+
+async fn dump_log_file(args: &DumpArgs, out: &mut impl Write) -> CustomResult<u64> {
+    let bytes = std::fs::metadata(&args.file)?.len();
+    let mut reader = BufReader::new(File::open(&args.file).await?);
+    let mut count = 0;
+    let mut offset = 0u32;
+    while (offset as u64) < bytes && args.to.map(|to| offset < to).unwrap_or(true) {
+        let (len, record) = match read_record(&mut reader).await {
+            Ok(res) => res,
+            Err(e) => {
+                let error = DumpError { offset, error: e.message };
+                let text = format!("offset={} 无法解析，停止读取: {}", error.offset, error.error);
+                args.print(out, &error, text)?;
+                break;
+            }
+        };
+        let key = String::from_utf8_lossy(&record.key).into_owned();
+        if args.matches(&key, offset) {
+            let value = String::from_utf8_lossy(&record.value);
+            let mut preview: String = value.chars().take(args.preview).collect();
+            if preview.len() < value.len() {
+                preview.push_str("...");
+            }
+            let view = RecordView {
+                offset,
+                len,
+                timestamp: record.timestamp,
+                flags: record.flags,
+                key,
+                value_len: record.value.len(),
+                value: preview,
+            };
+            let text = format!("offset={} len={} timestamp={} flags={:#04x} key={} value({})={}", view.offset,
+                               view.len, view.timestamp, view.flags, view.key, view.value_len, view.value);
+            args.print(out, &view, text)?;
+            count += 1;
+        }
+        offset += len;
+    }
+    Ok(count)
+}
+
+fn dump_index_file(args: &DumpArgs, out: &mut impl Write) -> CustomResult<u64> {
+    let mut count = 0;
+    for (key, offset) in read_index_file(Path::new(&args.file))? {
+        if args.matches(&key, offset) {
+            let text = format!("key={} offset={}", key, offset);
+            args.print(out, &IndexEntryView { key, offset }, text)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use crate::http_param::DataItem;
+    use crate::store::{get_index_file_name, get_log_file_name};
+    use crate::store::compression_task::generate_index_file;
+    use crate::store::record::Record;
+    use crate::tools::dump::{dump, DumpArgs};
+    use crate::test_util::test_workspace;
+
+    fn args(file: String) -> DumpArgs {
+        DumpArgs { file: PathBuf::from(file), key: None, from: None, to: None, preview: 4, json: false }
+    }
+
+    #[tokio::test]
+    async fn test_dump() {
+        let workspace = test_workspace("dump");
+
+        let mut data = Vec::new();
+        let mut offsets = Vec::new();
+        for i in 0..5 {
+            offsets.push(data.len() as u32);
+            Record::new(DataItem { key: format!("key_{}", i), value: format!("value_{}", i) }).encode_into(&mut data);
+        }
+        let log_file_name = get_log_file_name(1, &workspace);
+        let index_file_name = get_index_file_name(1, &workspace);
+        std::fs::write(&log_file_name, &data).unwrap();
+        generate_index_file(Path::new(&log_file_name), Path::new(&index_file_name)).await.unwrap();
+        // 末尾写坏的记录只影响数据文件
+        data.extend_from_slice(&[0, 1]);
+        std::fs::write(&log_file_name, &data).unwrap();
+
+        let mut out = Vec::new();
+        assert_eq!(dump(&args(log_file_name.clone()), &mut out).await.unwrap(), 5);
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(text.lines().count(), 6);
+        assert!(text.lines().next().unwrap().contains("key=key_0 value(7)=valu..."));
+
+        let mut log_args = args(log_file_name.clone());
+        log_args.from = Some(offsets[1]);
+        log_args.to = Some(offsets[3]);
+        log_args.json = true;
+        let mut out = Vec::new();
+        assert_eq!(dump(&log_args, &mut out).await.unwrap(), 2);
+        let first: serde_json::Value = serde_json::from_slice(out.split(|b| *b == b'\n').next().unwrap()).unwrap();
+        assert_eq!(first["key"], "key_1");
+        assert_eq!(first["offset"], offsets[1]);
+
+        let mut index_args = args(index_file_name);
+        index_args.key = Some(String::from("key_4"));
+        let mut out = Vec::new();
+        assert_eq!(dump(&index_args, &mut out).await.unwrap(), 1);
+        assert_eq!(String::from_utf8(out).unwrap(), format!("key=key_4 offset={}\n", offsets[4]));
+    }
+}
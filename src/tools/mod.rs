@@ -0,0 +1,54 @@
+use clap::Subcommand;
+
+use crate::custom_err::CustomResult;
+use crate::tools::dump::DumpArgs;
+use crate::tools::repair::RepairArgs;
+use crate::tools::upgrade::UpgradeArgs;
+use crate::tools::verify::VerifyArgs;
+
+pub mod dump;
+pub mod repair;
+pub mod upgrade;
+pub mod verify;
+
+/// 离线工具，执行完就退出，不会启动HTTP服务
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// 把旧格式的工作目录升级到当前程序的磁盘格式，升级时数据库不能运行
+    Upgrade(UpgradeArgs),
+    /// 检查工作目录的完整性：数据文件的格式和校验值、索引文件和数据文件是否一致
+    Verify(VerifyArgs),
+    /// 修复损坏的工作目录，损坏的数据移动到隔离目录，修复时数据库不能运行
+    Repair(RepairArgs),
+    /// 输出数据文件中的每条记录，或者索引文件中的key和位置
+    Dump(DumpArgs),
+}
+
+/// 执行离线工具，结果输出到标准输出，返回进程的退出码
+pub async fn run(command: Command) -> CustomResult<i32> {
+    match command {
+        Command::Upgrade(args) => {
+            let report = upgrade::upgrade(&args).await?;
+            print!("{}", report);
+            Ok(0)
+        }
+        Command::Verify(args) => {
+            let report = verify::verify(&args.workspace).await?;
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print!("{}", report);
+            }
+            Ok(if report.ok { 0 } else { 1 })
+        }
+        Command::Repair(args) => {
+            let report = repair::repair(&args).await?;
+            print!("{}", report);
+            Ok(if report.verified_ok || args.dry_run { 0 } else { 1 })
+        }
+        Command::Dump(args) => {
+            dump::dump(&args, &mut std::io::stdout().lock()).await?;
+            Ok(0)
+        }
+    }
+}
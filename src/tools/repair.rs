@@ -0,0 +1,284 @@
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+use clap::Args;
+
+use crate::custom_err::{corruption_err, invalid_argument_err, CustomResult};
+use crate::store::{get_index_file_name, get_log_file_name};
+use crate::store::record::{HEADER_LEN, Record, RecordHeader};
+use crate::store::compression_task::generate_index_file;
+use crate::store::workspace_lock::WorkspaceLock;
+use crate::tools::verify::{LogFileReport, verify};
+
+// 损坏的数据和孤立的索引文件移动到这个子目录，不直接删除
+const QUARANTINE_DIR_NAME: &str = "quarantine";
+// 修复过程中生成的临时文件后缀
+const REPAIR_FILE_SUFFIX: &str = ".repair";
+
+#[derive(Args, Debug)]
+pub struct RepairArgs {
+    /// 需要修复的工作目录，修复时数据库不能运行
+    #[arg(short, long)]
+    pub workspace: String,
+    /// 只输出修复报告，不修改任何文件
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// 修复报告
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    pub workspace: String,
+    pub dry_run: bool,
+    pub files: Vec<FileRepair>,
+    // 移动到隔离目录的孤立索引文件
+    pub quarantined_index_files: Vec<u32>,
+    // 修复后重新检查的结果
+    pub verified_ok: bool,
+}
+
+/// 单个数据文件的修复结果
+#[derive(Debug, Default)]
+pub struct FileRepair {
+    pub file_id: u32,
+    // 保留下来的记录数
+    pub records: u64,
+    // 无法解析的区域（位置，长度），已经移动到隔离目录
+    pub damaged_segments: Vec<(u64, u64)>,
+    // 重新生成了索引文件
+    pub index_rebuilt: bool,
+}
+
+/// 修复工作目录
+/// 1. 数据文件中无法解析的区域，从下一个能解析的记录头继续读取，损坏的区域写入隔离目录
+/// 2. 有效的记录原样写入新的数据文件，替换旧文件后重新生成索引文件
+/// 3. 数据文件完好但是索引文件有问题的，重新生成索引文件
+/// 4. 孤立的索引文件移动到隔离目录
+pub async fn repair(args: &RepairArgs) -> CustomResult<RepairReport> {
+    let workspace = &args.workspace;
+    let _lock = if args.dry_run { None } else { Some(WorkspaceLock::acquire(workspace)?) };
+
+    let before = verify(workspace).await?;
+    let mut report = RepairReport {
+        workspace: workspace.clone(),
+        dry_run: args.dry_run,
+        ..Default::default()
+    };
+    let last_file_id = before.files.last().map(|f| f.file_id);
+
+    for file in &before.files {
+        // 最新的文件可能本来就没有索引文件，其它文件修复后都要有索引文件
+        let sealed = Some(file.file_id) != last_file_id;
+        if let Some(repair) = repair_file(workspace, file, sealed, args.dry_run).await? {
+            report.files.push(repair);
+        }
+    }
+
+    for orphan in &before.orphan_index_files {
+        if !args.dry_run {
+            quarantine_file(workspace, &get_index_file_name(orphan.file_id, workspace))?;
+        }
+        report.quarantined_index_files.push(orphan.file_id);
+    }
+
+    report.verified_ok = if args.dry_run { before.ok } else { verify(workspace).await?.ok };
+    Ok(report)
+}
+
+async fn repair_file(workspace: &String, file: &LogFileReport, sealed: bool, dry_run: bool) -> CustomResult<Option<FileRepair>> {
+    let index_ok = file.index.as_ref().map(|index| {
+        index.error.is_none() && index.invalid_entries == 0 && index.missing_entries == 0
+    });
+    let rebuild_index = index_ok == Some(false) || (sealed && index_ok.is_none());
+    if file.corruption.is_none() && !rebuild_index {
+        return Ok(None);
+    }
+
+    let log_file_name = get_log_file_name(file.file_id, workspace);
+    let index_file_name = get_index_file_name(file.file_id, workspace);
+    let mut repair = FileRepair { file_id: file.file_id, records: file.records, ..Default::default() };
+
+    if file.corruption.is_some() {
+        let data = std::fs::read(&log_file_name)?;
+        let (clean, damaged, records) = salvage(&data);
+        repair.records = records;
+        repair.damaged_segments = damaged.iter().map(|(start, end)| (*start as u64, (end - start) as u64)).collect();
+        if dry_run {
+            return Ok(Some(repair));
+        }
+
+        // 先保存损坏的区域，再替换数据文件
+        let quarantine_dir = Path::new(workspace).join(QUARANTINE_DIR_NAME);
+        std::fs::create_dir_all(&quarantine_dir)?;
+        for (start, end) in &damaged {
+            let segment_name = quarantine_dir.join(format!("{}.{}.bad",
+                Path::new(&log_file_name).file_name().unwrap().to_string_lossy(), start));
+            write_synced(&segment_name.to_string_lossy(), &data[*start..*end])?;
+        }
+
+        let tmp_log_file_name = format!("{}{}", log_file_name, REPAIR_FILE_SUFFIX);
+        write_synced(&tmp_log_file_name, &clean)?;
+        // 旧的索引文件已经不对了，先删除，中断后启动时也会重新生成
+        if Path::new(&index_file_name).exists() {
+            std::fs::remove_file(&index_file_name)?;
+        }
+        std::fs::rename(&tmp_log_file_name, &log_file_name)?;
+        std::fs::File::open(workspace)?.sync_all()?;
+    } else if dry_run {
+        repair.index_rebuilt = true;
+        return Ok(Some(repair));
+    }
+
+    if sealed || file.index.is_some() {
+        generate_index_file(Path::new(&log_file_name), Path::new(&index_file_name)).await?;
+        repair.index_rebuilt = true;
+    }
+    Ok(Some(repair))
+}
+
+/// 从数据中挑出能解析的记录，遇到无法解析的位置时，逐字节向后查找下一条能解析的记录
+/// 返回有效的记录、损坏的区域（起止位置）和有效的记录数
+fn salvage(data: &[u8]) -> (Vec<u8>, Vec<(usize, usize)>, u64) {
+    let mut clean = Vec::with_capacity(data.len());
+    let mut damaged = Vec::new();
+    let mut records = 0;
+    let mut pos = 0;
+    let mut damaged_start = None;
+    while pos < data.len() {
+        match decode_record_len(&data[pos..]) {
+            Ok(len) => {
+                if let Some(start) = damaged_start.take() {
+                    damaged.push((start, pos));
+                }
+                // 原样保留，不改变写入时间等信息
+                clean.extend_from_slice(&data[pos..pos + len]);
+                records += 1;
+                pos += len;
+            }
+            Err(_) => {
+                damaged_start.get_or_insert(pos);
+                pos += 1;
+            }
+        }
+    }
+    if let Some(start) = damaged_start {
+        damaged.push((start, data.len()));
+    }
+    (clean, damaged, records)
+}
+
+/// 解析开头的一条记录，返回记录的长度
+/// 先用记录头里的长度和剩下的数据量比较，不够一条记录时直接返回错误，不分配内存也不复制数据，
+/// 否则逐字节查找时，每个像记录头的位置都会把文件剩下的部分读一遍
+fn decode_record_len(data: &[u8]) -> CustomResult<usize> {
+    let header = RecordHeader::decode(data)?;
+    let len = HEADER_LEN + header.body_len();
+    if len > data.len() {
+        return Err(corruption_err(format!("记录长度{}超过剩余的数据{}", len, data.len())));
+    }
+    Record::decode(&header, &data[HEADER_LEN..len])?.into_item()?;
+    Ok(len)
+}
+
+/// 把文件移动到隔离目录
+fn quarantine_file(workspace: &String, file_name: &String) -> CustomResult<()> {
+    let quarantine_dir = Path::new(workspace).join(QUARANTINE_DIR_NAME);
+    std::fs::create_dir_all(&quarantine_dir)?;
+    let target = quarantine_dir.join(Path::new(file_name).file_name()
+        .ok_or(invalid_argument_err(format!("文件名不合法:{}", file_name)))?);
+    std::fs::rename(file_name, target)?;
+    Ok(())
+}
+
+fn write_synced(file_name: &str, data: &[u8]) -> CustomResult<()> {
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(file_name)?;
+    file.write_all(data)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+impl fmt::Display for RepairReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "workspace: {}", self.workspace)?;
+        for file in &self.files {
+            writeln!(f, "{}: 保留{}条记录", get_log_file_name(file.file_id, &self.workspace), file.records)?;
+            for (offset, len) in &file.damaged_segments {
+                writeln!(f, "    位置{}开始的{}字节损坏，移动到{}目录", offset, len, QUARANTINE_DIR_NAME)?;
+            }
+            if file.index_rebuilt {
+                writeln!(f, "    重新生成索引文件")?;
+            }
+        }
+        for file_id in &self.quarantined_index_files {
+            writeln!(f, "{}: 没有对应的数据文件，移动到{}目录", get_index_file_name(*file_id, &self.workspace),
+                     QUARANTINE_DIR_NAME)?;
+        }
+        if self.dry_run {
+            writeln!(f, "dry-run，没有修改任何文件")
+        } else if self.verified_ok {
+            writeln!(f, "修复完成，检查通过")
+        } else {
+            writeln!(f, "修复完成，仍然有问题，请执行 verify 查看")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::config::Config;
+    use crate::http_param::DataItem;
+    use crate::store::{get_index_file_name, get_log_file_name};
+    use crate::store::compression_task::write_index_file;
+    use crate::store::data_manager::DataManager;
+    use crate::store::manifest::{FORMAT_VERSION, Manifest};
+    use crate::store::record::Record;
+    use crate::tools::repair::{QUARANTINE_DIR_NAME, repair, RepairArgs};
+    use crate::tools::verify::verify;
+    use crate::test_util::test_workspace;
+
+    #[tokio::test]
+    async fn test_repair() {
+        let workspace = test_workspace("repair");
+        Manifest::new(&Config::new(workspace.clone()), FORMAT_VERSION).write(&workspace).unwrap();
+
+        // 第3条记录中间损坏，末尾有写到一半的数据
+        let mut data = Vec::new();
+        let mut damaged_at = 0;
+        for i in 0..10 {
+            if i == 3 {
+                damaged_at = data.len() + 30;
+            }
+            Record::new(DataItem { key: format!("key_{}", i), value: format!("value_{}", i) }).encode_into(&mut data);
+        }
+        data[damaged_at] ^= 0xff;
+        data.extend_from_slice(&[0, 1, 2]);
+        std::fs::write(get_log_file_name(1, &workspace), &data).unwrap();
+        std::fs::write(get_log_file_name(2, &workspace), b"").unwrap();
+        write_index_file(Path::new(&get_index_file_name(1, &workspace)), &[(String::from("key_0"), 0)]).await.unwrap();
+        write_index_file(Path::new(&get_index_file_name(5, &workspace)), &[(String::from("lost"), 0)]).await.unwrap();
+        assert!(!verify(&workspace).await.unwrap().ok);
+
+        let report = repair(&RepairArgs { workspace: workspace.clone(), dry_run: true }).await.unwrap();
+        assert_eq!(report.files[0].damaged_segments.len(), 2);
+        assert_eq!(std::fs::read(get_log_file_name(1, &workspace)).unwrap(), data);
+
+        let report = repair(&RepairArgs { workspace: workspace.clone(), dry_run: false }).await.unwrap();
+        assert!(report.verified_ok, "{}", report);
+        assert_eq!(report.files[0].records, 9);
+        assert_eq!(report.quarantined_index_files, vec![5]);
+        let quarantined = std::fs::read_dir(std::path::Path::new(workspace.as_str()).join(QUARANTINE_DIR_NAME)).unwrap().count();
+        assert_eq!(quarantined, 3);
+
+        let mut cnf = Config::new(workspace.to_string());
+        cnf.read_only = true;
+        let dm = DataManager::new(cnf).await.unwrap();
+        assert_eq!(dm.find(&String::from("key_3")).await.unwrap(), None);
+        for i in [0, 2, 4, 9] {
+            assert_eq!(dm.find(&format!("key_{}", i)).await.unwrap(), Some(format!("value_{}", i)));
+        }
+    }
+}
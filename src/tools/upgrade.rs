@@ -0,0 +1,345 @@
+use std::fmt;
+use std::path::Path;
+
+use clap::Args;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+
+use crate::config::Config;
+use crate::custom_err::{common_err, corruption_err, CustomResult, not_found_err, unsupported_format_err};
+use crate::http_param::DataItem;
+use crate::store::{get_index_file_name, get_log_file_name, read_record};
+use crate::store::compression_task::{scan_file_id_vec, write_index_file};
+use crate::store::manifest::{FORMAT_VERSION, Manifest};
+use crate::store::record::Record;
+use crate::store::workspace_lock::WorkspaceLock;
+
+// 升级过程中生成的临时文件后缀，全部生成后再统一替换
+const UPGRADE_FILE_SUFFIX: &str = ".upgrade";
+
+#[derive(Args, Debug)]
+pub struct UpgradeArgs {
+    /// 需要升级的工作目录
+    #[arg(short, long)]
+    pub workspace: String,
+    /// 只输出升级报告，不修改任何文件
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// 升级报告
+#[derive(Debug, Default)]
+pub struct UpgradeReport {
+    pub workspace: String,
+    pub from_version: u32,
+    pub to_version: u32,
+    pub dry_run: bool,
+    // 上次升级中断，这次只做了替换文件
+    pub resumed: bool,
+    pub files: Vec<FileReport>,
+}
+
+/// 单个数据文件的升级结果
+#[derive(Debug, Default)]
+pub struct FileReport {
+    pub file_id: u32,
+    pub records: u64,
+    pub old_bytes: u64,
+    pub new_bytes: u64,
+    // 文件末尾写到一半的字节数，升级后会被丢弃
+    pub discarded_bytes: u64,
+}
+
+/// 升级工作目录，保证任何时候中断都可以重新执行
+/// 1. 每个数据文件转换成新格式的临时文件，同时生成新的索引临时文件，全部落盘
+/// 2. 在描述文件中记录正在升级，这之后旧文件就不再需要了
+/// 3. 用临时文件替换旧文件，最后更新描述文件中的版本
+///
+/// 第2步之前中断，旧文件没有任何修改，重新执行即可；第2步之后中断，重新执行时只做第3步
+pub async fn upgrade(args: &UpgradeArgs) -> CustomResult<UpgradeReport> {
+    let workspace = &args.workspace;
+    if !Path::new(workspace).is_dir() {
+        return Err(not_found_err(format!("workspace[{}]不存在", workspace)));
+    }
+    // 升级时不能有其它进程在写入
+    let _lock = if args.dry_run { None } else { Some(WorkspaceLock::acquire(workspace)?) };
+
+    let (mut manifest, _) = Manifest::read_or_detect(&Config::new(workspace.clone()))?;
+    let mut report = UpgradeReport {
+        workspace: workspace.clone(),
+        from_version: manifest.format_version,
+        to_version: FORMAT_VERSION,
+        dry_run: args.dry_run,
+        ..Default::default()
+    };
+    if manifest.format_version > FORMAT_VERSION {
+        return Err(unsupported_format_err(format!("workspace[{}]的磁盘格式版本是{}，当前程序最高只支持{}",
+                                                  workspace, manifest.format_version, FORMAT_VERSION)));
+    }
+
+    if manifest.upgrading_to.is_some() {
+        report.resumed = true;
+        if !args.dry_run {
+            finish(workspace, &mut manifest).await?;
+        }
+        return Ok(report);
+    }
+    if manifest.format_version == FORMAT_VERSION {
+        return Ok(report);
+    }
+
+    // 目前只有版本1需要升级
+    for file_id in scan_file_id_vec(workspace) {
+        report.files.push(convert_legacy_file(workspace, file_id, args.dry_run).await?);
+    }
+    if args.dry_run {
+        return Ok(report);
+    }
+
+    manifest.upgrading_to = Some(FORMAT_VERSION);
+    manifest.write(workspace)?;
+    finish(workspace, &mut manifest).await?;
+    Ok(report)
+}
+
+/// 用临时文件替换旧文件，然后更新描述文件中的版本
+async fn finish(workspace: &String, manifest: &mut Manifest) -> CustomResult<()> {
+    for file_id in scan_file_id_vec(workspace) {
+        for file_name in [get_index_file_name(file_id, workspace), get_log_file_name(file_id, workspace)] {
+            let tmp_file_name = format!("{}{}", file_name, UPGRADE_FILE_SUFFIX);
+            // 已经替换过的文件没有临时文件
+            if Path::new(&tmp_file_name).exists() {
+                std::fs::rename(&tmp_file_name, &file_name)?;
+            }
+        }
+    }
+    std::fs::File::open(workspace)?.sync_all()?;
+
+    manifest.format_version = manifest.upgrading_to.take().unwrap_or(FORMAT_VERSION);
+    manifest.write(workspace)
+}
+
+/// 把版本1的数据文件转换成新格式的临时文件，并生成对应的索引临时文件
+async fn convert_legacy_file(workspace: &String, file_id: u32, dry_run: bool) -> CustomResult<FileReport> {
+    let log_file_name = get_log_file_name(file_id, workspace);
+    let mut report = FileReport {
+        file_id,
+        old_bytes: std::fs::metadata(&log_file_name)?.len(),
+        ..Default::default()
+    };
+
+    // 没有描述文件的版本2工作目录也会被当成版本1，这时第一条记录能通过新格式的校验，不能继续升级
+    if report.old_bytes > 0 && read_record(&mut BufReader::new(File::open(&log_file_name).await?)).await.is_ok() {
+        return Err(corruption_err(format!("{}已经是版本{}的格式，描述文件可能丢失了，停止升级", log_file_name, FORMAT_VERSION)));
+    }
+
+    let mut reader = BufReader::new(File::open(&log_file_name).await?);
+    let tmp_log_file_name = format!("{}{}", log_file_name, UPGRADE_FILE_SUFFIX);
+    let mut writer = if dry_run {
+        None
+    } else {
+        Some(BufWriter::new(File::create(&tmp_log_file_name).await?))
+    };
+
+    let mut entries = Vec::new();
+    let mut read_bytes = 0u64;
+    let mut buf = Vec::new();
+    while read_bytes < report.old_bytes {
+        let remaining = report.old_bytes - read_bytes;
+        let (len, item) = match read_legacy_item(&mut reader, remaining).await {
+            Ok(Some(res)) => res,
+            Ok(None) => {
+                log::warn!("{}的位置{}之后是写到一半的数据，丢弃{}字节", log_file_name, read_bytes, remaining);
+                report.discarded_bytes = remaining;
+                break;
+            }
+            // 中间的记录损坏时，后面的数据还在，不能丢弃，旧文件还没有修改，删除临时文件后停止升级
+            Err(e) => {
+                if writer.take().is_some() {
+                    let _ = std::fs::remove_file(&tmp_log_file_name);
+                }
+                return Err(corruption_err(format!("{}的位置{}无法解析,{}，停止升级，旧文件没有修改",
+                                                  log_file_name, read_bytes, e.message)));
+            }
+        };
+        read_bytes += len as u64;
+
+        let offset = u32::try_from(report.new_bytes)
+            .map_err(|_| common_err(format!("{}升级后超过了单个文件的最大长度", log_file_name)))?;
+        entries.push((item.key.clone(), offset));
+        // 旧格式没有记录写入时间，记为0
+        let mut record = Record::new(item);
+        record.timestamp = 0;
+        report.records += 1;
+        report.new_bytes += record.encoded_len() as u64;
+
+        if let Some(writer) = writer.as_mut() {
+            buf.clear();
+            record.encode_into(&mut buf);
+            writer.write_all(&buf).await?;
+        }
+    }
+
+    if let Some(mut writer) = writer {
+        writer.flush().await?;
+        writer.get_ref().sync_data().await?;
+        let tmp_index_file_name = format!("{}{}", get_index_file_name(file_id, workspace), UPGRADE_FILE_SUFFIX);
+        write_index_file(Path::new(&tmp_index_file_name), &entries).await?;
+    }
+    Ok(report)
+}
+
+/// 读取一条版本1的记录：长度(4) + DataItem的JSON
+/// remaining 是文件剩余的字节数，不够一条完整的记录时说明是写到一半的数据，返回None
+async fn read_legacy_item<R: AsyncRead + Unpin>(file: &mut R, remaining: u64) -> CustomResult<Option<(u32, DataItem)>> {
+    if remaining < 4 {
+        return Ok(None);
+    }
+    let len = file.read_u32().await?;
+    if 4 + len as u64 > remaining {
+        return Ok(None);
+    }
+    let mut buffer = vec![0u8; len as usize];
+    file.read_exact(&mut buffer).await?;
+    Ok(Some((len + 4, serde_json::from_slice(&buffer)?)))
+}
+
+impl fmt::Display for UpgradeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "workspace: {}", self.workspace)?;
+        if self.resumed {
+            let action = if self.dry_run { "需要继续替换文件" } else { "已完成替换" };
+            return writeln!(f, "上次升级到版本{}时中断，{}", self.to_version, action);
+        }
+        if self.from_version == self.to_version {
+            return writeln!(f, "磁盘格式已经是版本{}，不需要升级", self.to_version);
+        }
+        writeln!(f, "磁盘格式: {} -> {}", self.from_version, self.to_version)?;
+        let (mut records, mut old_bytes, mut new_bytes) = (0, 0, 0);
+        for file in &self.files {
+            writeln!(f, "  {}: {}条记录, {} -> {}字节, 丢弃{}字节", get_log_file_name(file.file_id, &self.workspace),
+                     file.records, file.old_bytes, file.new_bytes, file.discarded_bytes)?;
+            records += file.records;
+            old_bytes += file.old_bytes;
+            new_bytes += file.new_bytes;
+        }
+        writeln!(f, "合计: {}个文件, {}条记录, {} -> {}字节", self.files.len(), records, old_bytes, new_bytes)?;
+        if self.dry_run {
+            writeln!(f, "dry-run，没有修改任何文件")
+        } else {
+            writeln!(f, "升级完成")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::http_param::DataItem;
+    use crate::store::{get_index_file_name, get_log_file_name, get_manifest_file_name};
+    use crate::store::data_manager::DataManager;
+    use crate::store::manifest::{FORMAT_VERSION, LEGACY_FORMAT_VERSION, Manifest};
+    use crate::store::write_consumer::WriteEvent;
+    use crate::tools::upgrade::{convert_legacy_file, upgrade, UpgradeArgs};
+    use crate::test_util::{test_workspace, TestWorkspace};
+
+    /// 按版本1的格式生成工作目录，第二个文件末尾有写到一半的数据
+    fn legacy_workspace(name: &str) -> TestWorkspace {
+        let workspace = test_workspace(&format!("upgrade-{}", name));
+        for file_id in [1, 2] {
+            let mut buf = Vec::new();
+            for i in 0..10 {
+                let json = serde_json::to_vec(&DataItem {
+                    key: format!("key_{}", i),
+                    value: format!("value_{}_{}", file_id, i),
+                }).unwrap();
+                buf.extend_from_slice(&(json.len() as u32).to_be_bytes());
+                buf.extend_from_slice(&json);
+            }
+            if file_id == 2 {
+                buf.extend_from_slice(&[0, 0, 1]);
+            }
+            std::fs::write(get_log_file_name(file_id, &workspace), buf).unwrap();
+        }
+        // 旧的索引文件，升级后会被替换
+        std::fs::write(get_index_file_name(1, &workspace), [0u8; 3]).unwrap();
+        workspace
+    }
+
+    async fn assert_upgraded(workspace: &String) {
+        assert_eq!(Manifest::read(workspace).unwrap().unwrap().format_version, FORMAT_VERSION);
+        let mut cnf = Config::new(workspace.clone());
+        cnf.read_only = true;
+        let dm = DataManager::new(cnf).await.unwrap();
+        for i in 0..10 {
+            assert_eq!(dm.find(&format!("key_{}", i)).await.unwrap(), Some(format!("value_2_{}", i)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upgrade() {
+        let workspace = legacy_workspace("full");
+        let old_log = std::fs::read(get_log_file_name(1, &workspace)).unwrap();
+
+        let mut args = UpgradeArgs { workspace: workspace.to_string(), dry_run: true };
+        let report = upgrade(&args).await.unwrap();
+        assert_eq!(report.from_version, LEGACY_FORMAT_VERSION);
+        assert_eq!(report.files.iter().map(|f| f.records).sum::<u64>(), 20);
+        assert_eq!(report.files[1].discarded_bytes, 3);
+        // dry-run不修改任何文件
+        assert_eq!(std::fs::read(get_log_file_name(1, &workspace)).unwrap(), old_log);
+        assert!(Manifest::read(&workspace).unwrap().is_none());
+
+        args.dry_run = false;
+        upgrade(&args).await.unwrap();
+        assert_upgraded(&workspace).await;
+        // 再次执行不需要升级
+        assert!(upgrade(&args).await.unwrap().files.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_stops_on_damage() {
+        // 中间的记录损坏，不能当成写到一半的数据丢弃
+        let workspace = legacy_workspace("damaged");
+        let log_file_name = get_log_file_name(1, &workspace);
+        let mut old_log = std::fs::read(&log_file_name).unwrap();
+        let first_len = u32::from_be_bytes(old_log[0..4].try_into().unwrap()) as usize;
+        old_log[4 + first_len + 4] = b'x';
+        std::fs::write(&log_file_name, &old_log).unwrap();
+        let args = UpgradeArgs { workspace: workspace.to_string(), dry_run: false };
+        assert!(upgrade(&args).await.is_err());
+        assert_eq!(std::fs::read(&log_file_name).unwrap(), old_log);
+        assert!(!std::path::Path::new(&format!("{}.upgrade", log_file_name)).exists());
+        assert!(Manifest::read(&workspace).unwrap().is_none());
+
+        // 描述文件丢失的版本2工作目录，不会被当成版本1升级
+        let workspace = test_workspace("upgrade-lost-manifest");
+        let dm = DataManager::new(Config::new(workspace.clone())).await.unwrap();
+        dm.push(WriteEvent::new_simple_event(DataItem { key: String::from("a"), value: String::from("1") })).await.unwrap();
+        dm.shutdown().await;
+        std::fs::remove_file(get_manifest_file_name(&workspace)).unwrap();
+        let log_file_name = get_log_file_name(1, &workspace);
+        let old_log = std::fs::read(&log_file_name).unwrap();
+        assert!(upgrade(&UpgradeArgs { workspace: workspace.to_string(), dry_run: false }).await.is_err());
+        assert_eq!(std::fs::read(&log_file_name).unwrap(), old_log);
+    }
+
+    #[tokio::test]
+    async fn test_resume_interrupted_upgrade() {
+        let workspace = legacy_workspace("resume");
+        // 模拟替换了一个文件后中断
+        for file_id in [1, 2] {
+            convert_legacy_file(&workspace, file_id, false).await.unwrap();
+        }
+        let mut manifest = Manifest::new(&Config::new(workspace.clone()), LEGACY_FORMAT_VERSION);
+        manifest.upgrading_to = Some(FORMAT_VERSION);
+        manifest.write(&workspace).unwrap();
+        let log_file_name = get_log_file_name(1, &workspace);
+        std::fs::rename(format!("{}.upgrade", log_file_name), &log_file_name).unwrap();
+        assert!(DataManager::new(Config::new(workspace.clone())).await.is_err());
+
+        let report = upgrade(&UpgradeArgs { workspace: workspace.to_string(), dry_run: false }).await.unwrap();
+        assert!(report.resumed);
+        assert_upgraded(&workspace).await;
+    }
+}
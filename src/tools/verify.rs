@@ -0,0 +1,305 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+
+use clap::Args;
+use serde::Serialize;
+use tokio::fs::File;
+use tokio::io::BufReader;
+
+use crate::config::Config;
+use crate::custom_err::{CustomResult, needs_upgrade_err, not_found_err, unsupported_format_err};
+use crate::store::{get_index_file_id, get_index_file_name, get_log_file_name, read_data_item};
+use crate::store::compression_task::scan_file_id_vec;
+use crate::store::manifest::{FORMAT_VERSION, Manifest};
+use crate::store::recover_task::read_index_file;
+
+// 每类问题最多列出的条数，其余的只计数
+const MAX_SAMPLES: usize = 10;
+
+#[derive(Args, Debug)]
+pub struct VerifyArgs {
+    /// 需要检查的工作目录，检查时不会修改任何文件
+    #[arg(short, long)]
+    pub workspace: String,
+    /// 以JSON格式输出检查报告
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// 检查报告
+#[derive(Serialize, Debug, Default)]
+pub struct VerifyReport {
+    pub workspace: String,
+    pub format_version: u32,
+    // 没有发现任何问题
+    pub ok: bool,
+    pub files: Vec<LogFileReport>,
+    // 最小和最大文件ID之间没有数据文件的ID，整理时回收了数据文件就会出现，只作为提示
+    pub file_id_gaps: Vec<u32>,
+    // 没有对应数据文件的索引文件，其中的key指向了不存在的文件，也就是缺少了还在使用的数据文件
+    pub orphan_index_files: Vec<OrphanIndexReport>,
+}
+
+/// 单个数据文件的检查结果
+#[derive(Serialize, Debug, Default)]
+pub struct LogFileReport {
+    pub file_id: u32,
+    pub bytes: u64,
+    pub records: u64,
+    // 无法解析的区域，从这个位置开始到文件末尾都无法读取
+    pub corruption: Option<Corruption>,
+    // 没有索引文件时为空，正在写入的文件一般没有索引文件
+    pub index: Option<IndexReport>,
+}
+
+/// 无法解析的区域
+#[derive(Serialize, Debug)]
+pub struct Corruption {
+    pub offset: u64,
+    pub bytes: u64,
+    pub message: String,
+}
+
+/// 索引文件和数据文件对比的结果
+#[derive(Serialize, Debug, Default)]
+pub struct IndexReport {
+    pub entries: u64,
+    // 索引文件本身无法解析
+    pub error: Option<String>,
+    // 指向的位置没有记录，或者记录的key不一致
+    pub invalid_entries: u64,
+    pub invalid_samples: Vec<IndexEntry>,
+    // 数据文件中有、但是索引文件中没有的记录
+    pub missing_entries: u64,
+    pub missing_samples: Vec<IndexEntry>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+    pub key: String,
+    pub offset: u32,
+}
+
+/// 没有对应数据文件的索引文件
+#[derive(Serialize, Debug, Default)]
+pub struct OrphanIndexReport {
+    pub file_id: u32,
+    pub keys: u64,
+    pub key_samples: Vec<String>,
+}
+
+impl LogFileReport {
+    fn is_ok(&self) -> bool {
+        self.corruption.is_none() && self.index.as_ref().map(|index| {
+            index.error.is_none() && index.invalid_entries == 0 && index.missing_entries == 0
+        }).unwrap_or(true)
+    }
+}
+
+/// 检查工作目录，只读取文件，数据库运行时也可以执行
+pub async fn verify(workspace: &String) -> CustomResult<VerifyReport> {
+    if !Path::new(workspace).is_dir() {
+        return Err(not_found_err(format!("workspace[{}]不存在", workspace)));
+    }
+    let (manifest, _) = Manifest::read_or_detect(&Config::new(workspace.clone()))?;
+    if manifest.format_version > FORMAT_VERSION {
+        return Err(unsupported_format_err(format!("workspace[{}]的磁盘格式版本是{}，当前程序只能检查版本{}，请升级程序",
+                                                  workspace, manifest.format_version, FORMAT_VERSION)));
+    }
+    if manifest.format_version < FORMAT_VERSION || manifest.upgrading_to.is_some() {
+        return Err(needs_upgrade_err(format!("workspace[{}]的磁盘格式版本是{}，当前程序只能检查版本{}，请先执行 upgrade",
+                                             workspace, manifest.format_version, FORMAT_VERSION)));
+    }
+
+    let mut report = VerifyReport {
+        workspace: workspace.clone(),
+        format_version: manifest.format_version,
+        ..Default::default()
+    };
+    let file_ids = scan_file_id_vec(workspace);
+    for file_id in &file_ids {
+        report.files.push(verify_log_file(workspace, *file_id).await?);
+    }
+    if let (Some(first), Some(last)) = (file_ids.first(), file_ids.last()) {
+        let exists: HashSet<&u32> = file_ids.iter().collect();
+        report.file_id_gaps = (*first..*last).filter(|id| !exists.contains(id)).collect();
+    }
+
+    let index_ids: BTreeSet<u32> = std::fs::read_dir(workspace)?
+        .flatten()
+        .filter_map(|f| get_index_file_id(&f.path()))
+        .collect();
+    for file_id in index_ids.into_iter().filter(|id| !file_ids.contains(id)) {
+        let mut orphan = OrphanIndexReport { file_id, ..Default::default() };
+        if let Ok(entries) = read_index_file(Path::new(&get_index_file_name(file_id, workspace))) {
+            orphan.keys = entries.len() as u64;
+            orphan.key_samples = entries.into_iter().take(MAX_SAMPLES).map(|(key, _)| key).collect();
+        }
+        report.orphan_index_files.push(orphan);
+    }
+
+    report.ok = report.files.iter().all(|f| f.is_ok()) && report.orphan_index_files.is_empty();
+    Ok(report)
+}
+
+/// 逐条读取数据文件，校验格式和校验值，然后和索引文件对比
+async fn verify_log_file(workspace: &String, file_id: u32) -> CustomResult<LogFileReport> {
+    let log_file_name = get_log_file_name(file_id, workspace);
+    let mut report = LogFileReport {
+        file_id,
+        bytes: std::fs::metadata(&log_file_name)?.len(),
+        ..Default::default()
+    };
+
+    // 每条记录的位置和key
+    let mut records = HashMap::new();
+    let mut reader = BufReader::new(File::open(&log_file_name).await?);
+    let mut pos = 0u64;
+    while pos < report.bytes {
+        match read_data_item(&mut reader).await {
+            Ok((len, item)) => {
+                records.insert(pos as u32, item.key);
+                report.records += 1;
+                pos += len as u64;
+            }
+            Err(e) => {
+                report.corruption = Some(Corruption { offset: pos, bytes: report.bytes - pos, message: e.message });
+                break;
+            }
+        }
+    }
+
+    let index_file_name = get_index_file_name(file_id, workspace);
+    if Path::new(&index_file_name).exists() {
+        report.index = Some(verify_index_file(Path::new(&index_file_name), &records));
+    }
+    Ok(report)
+}
+
+fn verify_index_file(index_path: &Path, records: &HashMap<u32, String>) -> IndexReport {
+    let mut report = IndexReport::default();
+    let entries = match read_index_file(index_path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            report.error = Some(e.message);
+            return report;
+        }
+    };
+    report.entries = entries.len() as u64;
+
+    let mut indexed = HashSet::new();
+    for (key, offset) in entries {
+        if records.get(&offset) == Some(&key) {
+            indexed.insert(offset);
+            continue;
+        }
+        report.invalid_entries += 1;
+        if report.invalid_samples.len() < MAX_SAMPLES {
+            report.invalid_samples.push(IndexEntry { key, offset });
+        }
+    }
+
+    let mut missing: Vec<IndexEntry> = records.iter()
+        .filter(|(offset, _)| !indexed.contains(*offset))
+        .map(|(offset, key)| IndexEntry { key: key.clone(), offset: *offset })
+        .collect();
+    missing.sort_by_key(|entry| entry.offset);
+    report.missing_entries = missing.len() as u64;
+    report.missing_samples = missing.into_iter().take(MAX_SAMPLES).collect();
+    report
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "workspace: {} (磁盘格式版本{})", self.workspace, self.format_version)?;
+        for file in &self.files {
+            let status = if file.is_ok() { "OK" } else { "ERROR" };
+            writeln!(f, "[{}] {}: {}字节, {}条记录", status, get_log_file_name(file.file_id, &self.workspace),
+                     file.bytes, file.records)?;
+            if let Some(c) = &file.corruption {
+                writeln!(f, "    位置{}开始的{}字节无法解析: {}", c.offset, c.bytes, c.message)?;
+            }
+            match &file.index {
+                None => writeln!(f, "    没有索引文件")?,
+                Some(index) => {
+                    if let Some(e) = &index.error {
+                        writeln!(f, "    索引文件无法解析: {}", e)?;
+                    }
+                    for entry in &index.invalid_samples {
+                        writeln!(f, "    索引指向的位置没有对应的记录: key={} offset={}", entry.key, entry.offset)?;
+                    }
+                    for entry in &index.missing_samples {
+                        writeln!(f, "    记录不在索引中: key={} offset={}", entry.key, entry.offset)?;
+                    }
+                    if index.invalid_entries + index.missing_entries > 0 {
+                        writeln!(f, "    共{}条错误索引, {}条缺失索引", index.invalid_entries, index.missing_entries)?;
+                    }
+                }
+            }
+        }
+        if !self.file_id_gaps.is_empty() {
+            writeln!(f, "[INFO] 文件ID不连续，整理回收数据文件后是正常的: {:?}", self.file_id_gaps)?;
+        }
+        for orphan in &self.orphan_index_files {
+            writeln!(f, "[ERROR] {}没有对应的数据文件, 其中{}个key指向不存在的文件: {:?}",
+                     get_index_file_name(orphan.file_id, &self.workspace), orphan.keys, orphan.key_samples)?;
+        }
+        writeln!(f, "{}", if self.ok { "检查通过" } else { "发现问题" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use crate::config::Config;
+    use crate::http_param::DataItem;
+    use crate::store::{get_index_file_name, get_log_file_name};
+    use crate::store::compression_task::write_index_file;
+    use crate::store::data_manager::DataManager;
+    use crate::store::write_consumer::WriteEvent;
+    use crate::tools::verify::{IndexEntry, verify};
+    use crate::test_util::test_workspace;
+
+    #[tokio::test]
+    async fn test_verify() {
+        let workspace = test_workspace("verify");
+
+        let dm = DataManager::new(Config::new(workspace.clone())).await.unwrap();
+        for i in 0..10 {
+            dm.push(WriteEvent::new_simple_event(DataItem {
+                key: format!("key_{}", i),
+                value: format!("value_{}", i),
+            })).await.unwrap();
+        }
+        dm.shutdown().await;
+        let report = verify(&workspace).await.unwrap();
+        assert!(report.ok, "{}", report);
+        assert_eq!(report.files[0].records, 10);
+
+        // 回收后文件ID不连续是正常的
+        std::fs::write(get_log_file_name(3, &workspace), b"").unwrap();
+        let report = verify(&workspace).await.unwrap();
+        assert!(report.ok, "{}", report);
+        assert_eq!(report.file_id_gaps, vec![2]);
+
+        // 数据文件末尾损坏，索引文件少一条、错一条，还有一个孤立的索引文件
+        let mut log_file = std::fs::OpenOptions::new().append(true).open(get_log_file_name(1, &workspace)).unwrap();
+        log_file.write_all(&[1, 2, 3]).unwrap();
+        write_index_file(std::path::Path::new(&get_index_file_name(1, &workspace)),
+                         &[(String::from("key_0"), 0), (String::from("key_1"), 1)]).await.unwrap();
+        write_index_file(std::path::Path::new(&get_index_file_name(7, &workspace)),
+                         &[(String::from("lost"), 0)]).await.unwrap();
+
+        let report = verify(&workspace).await.unwrap();
+        assert!(!report.ok);
+        let file = &report.files[0];
+        assert_eq!(file.corruption.as_ref().unwrap().bytes, 3);
+        let index = file.index.as_ref().unwrap();
+        assert_eq!(index.invalid_samples, vec![IndexEntry { key: String::from("key_1"), offset: 1 }]);
+        assert_eq!(index.missing_entries, 9);
+        assert_eq!(report.orphan_index_files[0].key_samples, vec![String::from("lost")]);
+        assert!(serde_json::to_string(&report).is_ok());
+    }
+}